@@ -0,0 +1,111 @@
+// Address space identifiers.
+// satp tags every TLB entry with the ASID it was loaded under, so switching
+// between two address spaces with different ASIDs needs no flush. There
+// are far fewer ASIDs than there may be address spaces, so they are handed
+// out when an address space is switched to, together with the generation
+// they belong to. Once they run out, the generation goes up and everybody
+// needs a new ASID, and each hart flushes its TLB once before it switches
+// to one of them. ASID 0 is the kernel's and is never handed out.
+
+use crate::cpu;
+
+// The most ASIDs satp can hold.
+const ASID_LIMIT: usize = 1 << 16;
+const ASID_MASK: u64 = ASID_LIMIT as u64 - 1;
+
+// Highest ASID the hart implements, 0 if it has none at all.
+static mut ASID_MAX: usize = 0;
+// The generation sits above the ASID in a context, so a context of 0 is
+// one that never had an ASID.
+static mut GENERATION: u64 = ASID_LIMIT as u64;
+// One bit per ASID that is taken in the current generation.
+static mut USED: [u64; ASID_LIMIT / 64] = [0; ASID_LIMIT / 64];
+// Where to start looking for a free ASID.
+static mut NEXT: usize = 1;
+// Harts that have to flush their TLB before they switch to an ASID, since
+// the ASIDs were handed out again.
+static mut FLUSH_PENDING: usize = 0;
+
+/// Find out how many ASID bits the hart implements, by writing all ones
+/// into the field and seeing what sticks. Call this once paging is on.
+pub fn init() {
+    let satp = cpu::satp_read();
+    cpu::satp_write(satp | (ASID_LIMIT - 1) << 44);
+    let max = (cpu::satp_read() >> 44) & (ASID_LIMIT - 1);
+    cpu::satp_write(satp);
+    cpu::satp_fence_all();
+    unsafe {
+        ASID_MAX = max;
+    }
+}
+
+/// Number of ASID bits the hart implements.
+pub fn asid_bits() -> usize {
+    unsafe { ASID_MAX.count_ones() as usize }
+}
+
+unsafe fn is_used(asid: usize) -> bool {
+    USED[asid / 64] & 1 << (asid % 64) != 0
+}
+
+unsafe fn find_free() -> Option<usize> {
+    (NEXT..=ASID_MAX)
+        .chain(1..NEXT)
+        .find(|&asid| !is_used(asid))
+}
+
+unsafe fn rollover() {
+    GENERATION += ASID_LIMIT as u64;
+    USED = [0; ASID_LIMIT / 64];
+    NEXT = 1;
+    FLUSH_PENDING = (1 << cpu::KERNEL_TRAP_FRAME_COUNT) - 1;
+}
+
+/// Make sure context has an ASID of the current generation, handing out a
+/// new one if it does not, and return that ASID. Call this right before
+/// switching to the address space the context belongs to.
+pub fn get(context: &mut u64) -> u16 {
+    unsafe {
+        if ASID_MAX == 0 {
+            // Without ASIDs, everybody shares the kernel's and every switch
+            // has to flush.
+            *context = GENERATION;
+            cpu::satp_fence_all();
+            return 0;
+        }
+        if *context & !ASID_MASK != GENERATION {
+            let asid = match find_free() {
+                Some(asid) => asid,
+                None => {
+                    rollover();
+                    find_free().unwrap()
+                }
+            };
+            USED[asid / 64] |= 1 << (asid % 64);
+            NEXT = asid % ASID_MAX + 1;
+            *context = GENERATION | asid as u64;
+        }
+        let hart = 1 << cpu::hart_id();
+        if FLUSH_PENDING & hart != 0 {
+            FLUSH_PENDING &= !hart;
+            cpu::satp_fence_all();
+        }
+        (*context & ASID_MASK) as u16
+    }
+}
+
+/// The ASID context holds, which may be from an older generation.
+pub fn asid(context: u64) -> u16 {
+    (context & ASID_MASK) as u16
+}
+
+/// Give the ASID of context back. The TLB must not hold any entries for it
+/// anymore.
+pub fn put(context: u64) {
+    let asid = (context & ASID_MASK) as usize;
+    unsafe {
+        if context & !ASID_MASK == GENERATION && asid != 0 {
+            USED[asid / 64] &= !(1 << (asid % 64));
+        }
+    }
+}
@@ -0,0 +1,146 @@
+// The virtio block device, which the QEMU runner backs with hdd.dsk.
+// Requests go through virtio::Device::submit() and are finished by the
+// time read() and write() return. There is a single request buffer and
+// nothing here locks it, so requests must not overlap; swap.rs, the only
+// user, makes them under SWAP_LOCK.
+//
+// A request is a chain of three buffers: a header saying what to do, the
+// data, and a status byte the device writes back.
+
+use crate::page::{alloc_aligned, dealloc, virt_to_phys, DmaBuffer, PAGE_SIZE};
+use crate::virtio::{self, Descriptor, DESC_WRITE};
+use core::mem::size_of;
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Request types
+const TYPE_IN: u32 = 0;
+const TYPE_OUT: u32 = 1;
+
+// What the device puts into the status byte when all went well.
+const STATUS_OK: u8 = 0;
+
+#[repr(C)]
+struct Header {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlockError {
+    /// There is no block device.
+    NoDevice,
+    /// The buffer is not a whole number of sectors, or runs past the end
+    /// of the disk.
+    OutOfRange,
+    /// The device reported an error.
+    IoError,
+}
+
+struct Block {
+    dev: virtio::Device,
+    // Size of the disk in sectors.
+    capacity: u64,
+    // A page of the DMA zone for the request header, with the status byte
+    // right after it.
+    request: DmaBuffer,
+}
+
+static mut BLOCK: Option<Block> = None;
+
+/// Find the block device and set it up. Returns false if there is none.
+pub fn init() -> bool {
+    let base = match virtio::find(virtio::DEVICE_BLOCK) {
+        Some(base) => base,
+        None => return false,
+    };
+    let request = match alloc_aligned(1, PAGE_SIZE) {
+        Some(request) => request,
+        None => return false,
+    };
+    // We want none of the optional features.
+    let dev = match virtio::Device::new(base, 0) {
+        Some(dev) => dev,
+        None => {
+            dealloc(request.vaddr);
+            return false;
+        }
+    };
+    // The capacity is the first field of the configuration space, a 64-bit
+    // count of sectors.
+    let capacity = dev.config(0) as u64 | (dev.config(4) as u64) << 32;
+    unsafe {
+        BLOCK = Some(Block {
+            dev,
+            capacity,
+            request,
+        });
+    }
+    true
+}
+
+/// Size of the disk in sectors, 0 if there is none.
+pub fn capacity() -> u64 {
+    unsafe { BLOCK.as_ref().map_or(0, |block| block.capacity) }
+}
+
+// buf is a kernel address in the direct map, so the buffer is physically
+// contiguous as well.
+fn request(kind: u32, sector: u64, buf: usize, len: usize) -> Result<(), BlockError> {
+    let block = unsafe { BLOCK.as_mut().ok_or(BlockError::NoDevice)? };
+    let sectors = (len / SECTOR_SIZE) as u64;
+    if len == 0 || len % SECTOR_SIZE != 0 || sector + sectors > block.capacity {
+        return Err(BlockError::OutOfRange);
+    }
+    let header = block.request.vaddr as *mut Header;
+    let status = unsafe { block.request.vaddr.add(size_of::<Header>()) };
+    unsafe {
+        header.write_volatile(Header {
+            kind,
+            reserved: 0,
+            sector,
+        });
+        // Anything but STATUS_OK, in case the device never writes it.
+        status.write_volatile(0xff);
+    }
+    let data_flags = if kind == TYPE_IN { DESC_WRITE } else { 0 };
+    let chain = [
+        Descriptor {
+            addr: block.request.paddr as u64,
+            len: size_of::<Header>() as u32,
+            flags: 0,
+            next: 0,
+        },
+        Descriptor {
+            addr: virt_to_phys(buf) as u64,
+            len: len as u32,
+            flags: data_flags,
+            next: 0,
+        },
+        Descriptor {
+            addr: (block.request.paddr + size_of::<Header>()) as u64,
+            len: 1,
+            flags: DESC_WRITE,
+            next: 0,
+        },
+    ];
+    block.dev.submit(&chain);
+    if unsafe { status.read_volatile() } == STATUS_OK {
+        Ok(())
+    } else {
+        Err(BlockError::IoError)
+    }
+}
+
+/// Read len bytes starting at sector into buf, which has to be in the
+/// direct map.
+pub fn read(sector: u64, buf: *mut u8, len: usize) -> Result<(), BlockError> {
+    request(TYPE_IN, sector, buf as usize, len)
+}
+
+/// Write len bytes from buf, which has to be in the direct map, starting
+/// at sector.
+pub fn write(sector: u64, buf: *const u8, len: usize) -> Result<(), BlockError> {
+    request(TYPE_OUT, sector, buf as usize, len)
+}
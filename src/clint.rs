@@ -0,0 +1,61 @@
+// Core Local Interruptor (CLINT). It holds the software interrupt bit
+// (MSIP) and the timer compare register (MTIMECMP) of every hart, plus the
+// MTIME counter that all of them share.
+//
+// Both of its interrupts are machine level. m_trap_vector in trap.S passes
+// them on to supervisor mode, and takes an ecall from supervisor mode to
+// set the next timer interrupt, since only machine mode can clear it.
+
+use crate::page::phys_to_virt;
+
+// Register offsets from the CLINT base. MTIMECMP, at 0x4000, is only
+// written by m_trap_vector.
+const CLINT_MSIP: usize = 0x0;
+const CLINT_MTIME: usize = 0xbff8;
+
+// Where the CLINT sits on the QEMU virt machine. probe() replaces these
+// with what the device tree says. m_trap_vector reads CLINT_BASE too, by
+// its physical address.
+#[no_mangle]
+static mut CLINT_BASE: usize = 0x0200_0000;
+static mut CLINT_SIZE: usize = 0x1_0000;
+
+/// Look the CLINT up in the device tree.
+pub fn probe() {
+    if let Some((base, size)) = crate::fdt::find_device(&["riscv,clint0", "sifive,clint0"]) {
+        unsafe {
+            CLINT_BASE = base;
+            CLINT_SIZE = size;
+        }
+    }
+}
+
+/// The MMIO range of the CLINT as (base, size).
+pub fn mmio_range() -> (usize, usize) {
+    unsafe { (CLINT_BASE, CLINT_SIZE) }
+}
+
+fn reg(offset: usize) -> usize {
+    unsafe { phys_to_virt(CLINT_BASE + offset) }
+}
+
+/// The current time. QEMU counts at 10_000_000 Hz.
+pub fn mtime() -> u64 {
+    unsafe { (reg(CLINT_MTIME) as *const u64).read_volatile() }
+}
+
+/// Fire the timer interrupt of this hart once mtime reaches val. This also
+/// acknowledges the one that is pending, if any. Machine mode writes
+/// MTIMECMP for us, see m_trap_vector.
+pub fn set_timer(val: u64) {
+    unsafe {
+        llvm_asm!("ecall" :: "{a0}"(val) : "memory" : "volatile");
+    }
+}
+
+/// Raise a software interrupt on the given hart.
+pub fn send_software_interrupt(hart: usize) {
+    unsafe {
+        (reg(CLINT_MSIP) as *mut u32).add(hart).write_volatile(1);
+    }
+}
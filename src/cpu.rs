@@ -1,10 +1,11 @@
 use core::ptr::null_mut;
 
 #[repr(usize)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum SatpMode {
     Off = 0,
     Sv39 = 8,
-    Sc48 = 9,
+    Sv48 = 9,
 }
 
 #[repr(C)]
@@ -29,10 +30,12 @@ impl TrapFrame {
     }
 }
 
-pub static mut KERNEL_TRAP_FRAME: [TrapFrame; 8] = [TrapFrame::zero(); 8];
+pub const KERNEL_TRAP_FRAME_COUNT: usize = 8;
+pub static mut KERNEL_TRAP_FRAME: [TrapFrame; KERNEL_TRAP_FRAME_COUNT] =
+    [TrapFrame::zero(); KERNEL_TRAP_FRAME_COUNT];
 
 pub const fn build_satp(mode: SatpMode, asid: usize, addr: usize) -> usize {
-    (mode as usize) << 60 | (asid & 0xffff) << 44 | (addr >> 12) & 0xff_ffff_ffff
+    (mode as usize) << 60 | (asid & 0xffff) << 44 | (addr >> 12) & 0xfff_ffff_ffff
 }
 
 pub fn mhartid_read() -> usize {
@@ -115,6 +118,32 @@ pub fn sscratch_swap(to: usize) -> usize {
     }
 }
 
+/// The hart we are running on. Supervisor mode cannot read mhartid, but
+/// sscratch always points at this hart's kernel trap frame.
+pub fn hart_id() -> usize {
+    unsafe { (*(sscratch_read() as *const TrapFrame)).hart_id }
+}
+
+/// Turn supervisor interrupts off on this hart, and return whether they
+/// were on, for interrupts_restore().
+pub fn interrupts_disable() -> bool {
+    unsafe {
+        let rval: usize;
+        llvm_asm!("csrrci   $0, sstatus, 1 << 1" : "=r"(rval) :: "memory" : "volatile");
+        rval & 1 << 1 != 0
+    }
+}
+
+/// Turn supervisor interrupts back on if interrupts_disable() found them
+/// on.
+pub fn interrupts_restore(on: bool) {
+    if on {
+        unsafe {
+            llvm_asm!("csrsi    sstatus, 1 << 1" ::: "memory" : "volatile");
+        }
+    }
+}
+
 pub fn sepc_write(val: usize) {
     unsafe {
         llvm_asm!("csrw     sepc, $0" :: "r"(val));
@@ -129,6 +158,12 @@ pub fn sepc_read() -> usize {
     }
 }
 
+pub fn sip_clear(mask: usize) {
+    unsafe {
+        llvm_asm!("csrc     sip, $0" :: "r"(mask));
+    }
+}
+
 pub fn satp_write(val: usize) {
     unsafe {
         llvm_asm!("csrw     satp, $0" :: "r"(val));
@@ -154,3 +189,15 @@ pub fn satp_fence_asid(asid: usize) {
         llvm_asm!("sfence.vma zero, $0" :: "r"(asid));
     }
 }
+
+pub fn satp_fence_vaddr(vaddr: usize) {
+    unsafe {
+        llvm_asm!("sfence.vma $0, zero" :: "r"(vaddr));
+    }
+}
+
+pub fn satp_fence_all() {
+    unsafe {
+        llvm_asm!("sfence.vma zero, zero");
+    }
+}
@@ -0,0 +1,363 @@
+// Flattened device tree (FDT) parsing.
+// QEMU hands the address of a device tree blob to _start in a1. The blob
+// describes how much RAM we have, how many harts there are and where the
+// devices live. Everything here works directly on the blob without
+// allocating, since we need it before the page allocator exists.
+//
+// The blob is big-endian and laid out as:
+// header | memory reservation block | structure block | strings block
+// The structure block is a stream of 32-bit tokens:
+// BEGIN_NODE name | PROP len nameoff value | END_NODE | NOP | END
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+
+// Byte offsets of the header fields we use.
+const HDR_TOTALSIZE: usize = 4;
+const HDR_OFF_DT_STRUCT: usize = 8;
+const HDR_OFF_DT_STRINGS: usize = 12;
+const HDR_OFF_MEM_RSVMAP: usize = 16;
+
+// Deeper than anything QEMU generates.
+const MAX_DEPTH: usize = 16;
+
+static mut FDT: Option<Fdt> = None;
+
+fn be32(bytes: &[u8], off: usize) -> Option<u32> {
+    let b = bytes.get(off..off + 4)?;
+    Some(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn be64(bytes: &[u8], off: usize) -> Option<u64> {
+    Some((be32(bytes, off)? as u64) << 32 | be32(bytes, off + 4)? as u64)
+}
+
+// Read a value made of `cells` 32-bit cells.
+fn cells(bytes: &[u8], off: usize, cells: u32) -> Option<usize> {
+    let mut val = 0usize;
+    for i in 0..cells as usize {
+        val = val << 32 | be32(bytes, off + i * 4)? as usize;
+    }
+    Some(val)
+}
+
+// The NUL-terminated string at off.
+fn cstr(bytes: &[u8], off: usize) -> Option<&str> {
+    let rest = bytes.get(off..)?;
+    let len = rest.iter().position(|&b| b == 0)?;
+    core::str::from_utf8(&rest[..len]).ok()
+}
+
+const fn align4(val: usize) -> usize {
+    (val + 3) & !3
+}
+
+/// A validated device tree blob.
+#[derive(Clone, Copy)]
+pub struct Fdt {
+    blob: &'static [u8],
+}
+
+impl Fdt {
+    /// Check the header at addr and take the blob as it is.
+    ///
+    /// # Safety
+    /// addr must point at memory that stays readable for as long as the
+    /// Fdt is used.
+    pub unsafe fn from_addr(addr: usize) -> Option<Fdt> {
+        if addr == 0 || addr % 8 != 0 {
+            return None;
+        }
+        let header = core::slice::from_raw_parts(addr as *const u8, 40);
+        if be32(header, 0)? != FDT_MAGIC {
+            return None;
+        }
+        let size = be32(header, HDR_TOTALSIZE)? as usize;
+        Some(Fdt {
+            blob: core::slice::from_raw_parts(addr as *const u8, size),
+        })
+    }
+
+    pub fn addr(&self) -> usize {
+        self.blob.as_ptr() as usize
+    }
+
+    pub fn size(&self) -> usize {
+        self.blob.len()
+    }
+
+    fn structs(&self) -> &'static [u8] {
+        let off = be32(self.blob, HDR_OFF_DT_STRUCT).unwrap_or(0) as usize;
+        self.blob.get(off..).unwrap_or(&[])
+    }
+
+    fn strings(&self) -> &'static [u8] {
+        let off = be32(self.blob, HDR_OFF_DT_STRINGS).unwrap_or(0) as usize;
+        self.blob.get(off..).unwrap_or(&[])
+    }
+
+    /// Every node, depth first, starting with the root.
+    pub fn nodes(&self) -> Nodes {
+        Nodes {
+            fdt: *self,
+            off: 0,
+            depth: 0,
+            cells: [(2, 1); MAX_DEPTH + 1],
+        }
+    }
+
+    /// Physical ranges the firmware wants us to keep our hands off, from
+    /// the memory reservation block.
+    pub fn reservations(&self) -> Reservations {
+        Reservations {
+            fdt: *self,
+            off: be32(self.blob, HDR_OFF_MEM_RSVMAP).unwrap_or(0) as usize,
+        }
+    }
+
+    /// The first node that lists compat in its "compatible" property.
+    pub fn find_compatible(&self, compat: &str) -> Option<Node> {
+        self.nodes().find(|node| node.is_compatible(compat))
+    }
+
+    /// Every (address, size) pair of all nodes with device_type "memory".
+    pub fn memory(&self) -> impl Iterator<Item = (usize, usize)> {
+        self.nodes()
+            .filter(|node| node.prop_str("device_type") == Some("memory"))
+            .flat_map(|node| node.reg())
+    }
+
+    /// Number of nodes with device_type "cpu", which is one per hart.
+    pub fn hart_count(&self) -> usize {
+        self.nodes()
+            .filter(|node| node.prop_str("device_type") == Some("cpu"))
+            .count()
+    }
+}
+
+/// One node of the tree.
+#[derive(Clone, Copy)]
+pub struct Node {
+    pub name: &'static str,
+    pub depth: usize,
+    // #address-cells and #size-cells of the parent, which is what the
+    // reg property of this node is written in.
+    addr_cells: u32,
+    size_cells: u32,
+    // Offset into the structure block of the node's first property.
+    props: usize,
+    fdt: Fdt,
+}
+
+impl Node {
+    /// (name, value) of every property of this node.
+    pub fn props(&self) -> Props {
+        Props {
+            fdt: self.fdt,
+            off: self.props,
+        }
+    }
+
+    pub fn prop(&self, name: &str) -> Option<&'static [u8]> {
+        self.props()
+            .find(|&(n, _)| n == name)
+            .map(|(_, value)| value)
+    }
+
+    pub fn prop_u32(&self, name: &str) -> Option<u32> {
+        be32(self.prop(name)?, 0)
+    }
+
+    /// The first string of a string (or string list) property.
+    pub fn prop_str(&self, name: &str) -> Option<&'static str> {
+        cstr(self.prop(name)?, 0)
+    }
+
+    /// "compatible" is a list of NUL-separated strings.
+    pub fn is_compatible(&self, compat: &str) -> bool {
+        self.prop("compatible").map_or(false, |value| {
+            value.split(|&b| b == 0).any(|s| s == compat.as_bytes())
+        })
+    }
+
+    /// The (address, size) pairs of the reg property.
+    pub fn reg(&self) -> Reg {
+        Reg {
+            value: self.prop("reg").unwrap_or(&[]),
+            off: 0,
+            addr_cells: self.addr_cells,
+            size_cells: self.size_cells,
+        }
+    }
+
+    /// Interrupt numbers from the interrupts property. We only deal with
+    /// the PLIC, which uses one cell per interrupt.
+    pub fn interrupts(&self) -> impl Iterator<Item = u32> {
+        let value = self.prop("interrupts").unwrap_or(&[]);
+        (0..value.len() / 4).filter_map(move |i| be32(value, i * 4))
+    }
+}
+
+pub struct Nodes {
+    fdt: Fdt,
+    off: usize,
+    depth: usize,
+    // (#address-cells, #size-cells) that the children of the node open
+    // at each depth use. Index 0 holds the defaults for the root itself.
+    cells: [(u32, u32); MAX_DEPTH + 1],
+}
+
+impl Iterator for Nodes {
+    type Item = Node;
+
+    fn next(&mut self) -> Option<Node> {
+        let structs = self.fdt.structs();
+        loop {
+            let token = be32(structs, self.off)?;
+            self.off += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = cstr(structs, self.off)?;
+                    self.off = align4(self.off + name.len() + 1);
+                    let (addr_cells, size_cells) = self.cells[self.depth.min(MAX_DEPTH)];
+                    let node = Node {
+                        name,
+                        depth: self.depth,
+                        addr_cells,
+                        size_cells,
+                        props: self.off,
+                        fdt: self.fdt,
+                    };
+                    self.depth += 1;
+                    if self.depth <= MAX_DEPTH {
+                        self.cells[self.depth] = (
+                            node.prop_u32("#address-cells").unwrap_or(2),
+                            node.prop_u32("#size-cells").unwrap_or(1),
+                        );
+                    }
+                    return Some(node);
+                }
+                FDT_END_NODE => {
+                    self.depth = self.depth.checked_sub(1)?;
+                }
+                FDT_PROP => {
+                    let len = be32(structs, self.off)? as usize;
+                    self.off += 8 + align4(len);
+                }
+                FDT_NOP => {}
+                // FDT_END or garbage
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct Props {
+    fdt: Fdt,
+    off: usize,
+}
+
+impl Iterator for Props {
+    type Item = (&'static str, &'static [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let structs = self.fdt.structs();
+        loop {
+            match be32(structs, self.off)? {
+                FDT_PROP => {
+                    let len = be32(structs, self.off + 4)? as usize;
+                    let nameoff = be32(structs, self.off + 8)? as usize;
+                    let value = structs.get(self.off + 12..self.off + 12 + len)?;
+                    self.off += 12 + align4(len);
+                    return Some((cstr(self.fdt.strings(), nameoff)?, value));
+                }
+                FDT_NOP => self.off += 4,
+                // The first child or the end of this node.
+                _ => return None,
+            }
+        }
+    }
+}
+
+pub struct Reg {
+    value: &'static [u8],
+    off: usize,
+    addr_cells: u32,
+    size_cells: u32,
+}
+
+impl Iterator for Reg {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        // Entries without any cells would never use the value up.
+        if self.addr_cells + self.size_cells == 0 {
+            return None;
+        }
+        let addr = cells(self.value, self.off, self.addr_cells)?;
+        let size = cells(
+            self.value,
+            self.off + self.addr_cells as usize * 4,
+            self.size_cells,
+        )?;
+        self.off += (self.addr_cells + self.size_cells) as usize * 4;
+        Some((addr, size))
+    }
+}
+
+pub struct Reservations {
+    fdt: Fdt,
+    off: usize,
+}
+
+impl Iterator for Reservations {
+    type Item = (usize, usize);
+
+    fn next(&mut self) -> Option<(usize, usize)> {
+        let addr = be64(self.fdt.blob, self.off)? as usize;
+        let size = be64(self.fdt.blob, self.off + 8)? as usize;
+        // The block ends with an all-zero entry.
+        if addr == 0 && size == 0 {
+            return None;
+        }
+        self.off += 16;
+        Some((addr, size))
+    }
+}
+
+/// Take the blob QEMU passed us. Returns false if there is no valid blob
+/// at addr, in which case get() keeps returning None and everybody falls
+/// back to the defaults for the QEMU virt machine.
+pub fn init(addr: usize) -> bool {
+    unsafe {
+        FDT = Fdt::from_addr(addr);
+        FDT.is_some()
+    }
+}
+
+/// The device tree passed in at boot, if there was a valid one.
+pub fn get() -> Option<Fdt> {
+    unsafe { FDT }
+}
+
+// The first node that is compatible with any of the given strings.
+fn find_node(compats: &[&str]) -> Option<Node> {
+    let fdt = get()?;
+    compats
+        .iter()
+        .find_map(|compat| fdt.find_compatible(compat))
+}
+
+/// The first (address, size) of the reg property of the first node that is
+/// compatible with any of the given strings.
+pub fn find_device(compats: &[&str]) -> Option<(usize, usize)> {
+    find_node(compats).and_then(|node| node.reg().next())
+}
+
+/// The first interrupt of that same node.
+pub fn find_interrupt(compats: &[&str]) -> Option<u32> {
+    find_node(compats).and_then(|node| node.interrupts().next())
+}
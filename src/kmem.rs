@@ -1,199 +1,940 @@
-use crate::page::{align_val, zalloc, Table, PAGE_SIZE};
-use crate::println;
-use core::{mem::size_of, ptr::null_mut};
+use crate::lock::IrqLock;
+use crate::oom::{self, Reclaim};
+use crate::page::{
+    align_val, alloc, alloc_align, allocation_pages, allocation_start, dealloc,
+    live_allocation_start, resize, zalloc, Table, PAGE_ORDER, PAGE_SIZE, VMALLOC_END,
+    VMALLOC_START,
+};
+use crate::vmalloc::{self, vfree, vmalloc};
+use crate::{clint, cpu, println};
+use alloc::boxed::Box;
+use core::{
+    cmp::Reverse,
+    mem::size_of,
+    panic::Location,
+    ptr::{null_mut, NonNull},
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
-#[repr(usize)]
-enum AllocListFlags {
-    Taken = 1 << 63,
+// ///////////////////////////////////
+// / SLAB ALLOCATOR
+// ///////////////////////////////////
+// Small allocations come out of slab caches, one for each power-of-two
+// size class from 8 bytes to 2 KiB. A slab is a run of pages from the page
+// allocator with a header at the start and objects of a single class after
+// it. The free objects of a slab are linked through their first word, and
+// slabs that have free objects sit on their cache's partial list, so both
+// kmalloc() and kfree() take constant time. Objects are aligned to their
+// size.
+//
+// Anything bigger than the largest class gets pages of its own: a run of
+// contiguous pages if the page allocator still has one, and otherwise
+// single pages mapped next to each other by vmalloc(). The heap therefore
+// grows for as long as there are free pages anywhere. kfree() tells them
+// all apart by where the pointer is: a vmalloc area is in its own region,
+// a page run starts at the first page of its page allocation, and a slab
+// object never does, since the slab header is there.
+//
+// An alignment is honored by picking a class at least that big, or for
+// anything past the largest class, a page run that starts on such a
+// boundary. Page runs are also what realloc can grow in place, by taking
+// the free pages right behind them.
+//
+// Slabs without a free object sit on their cache's full list, so that
+// check() can find every slab.
+//
+// In front of the caches, every hart has a magazine per class: a stack of
+// objects it allocated from the cache and can hand out again, or that it
+// freed and has not given back yet. kmalloc() and kfree() mostly stay
+// within the magazine, which only its own hart touches, with interrupts
+// off, so it needs no lock. shrink() cannot empty the magazines of other
+// harts itself; it leaves a request that each of them picks up the next
+// time it uses its own. An empty magazine is refilled with
+// MAGAZINE_BATCH objects at once, and a full one gives MAGAZINE_BATCH
+// back, so KMEM_LOCK is only taken once per batch. kfree() finds the slab
+// of an object through the page descriptors without PAGE_LOCK (see
+// page::live_allocation_start()), so an object that goes into the
+// magazine takes no lock at all.
+
+// Classes go from 2^MIN_CLASS_ORDER to 2^MAX_CLASS_ORDER bytes.
+const MIN_CLASS_ORDER: usize = 3;
+const MAX_CLASS_ORDER: usize = 11;
+const CLASSES: usize = MAX_CLASS_ORDER - MIN_CLASS_ORDER + 1;
+
+/// The largest allocation that comes out of a slab cache.
+pub const MAX_SLAB_SIZE: usize = 1 << MAX_CLASS_ORDER;
+
+// A slab is big enough for at least this many objects of its class, give
+// or take the header.
+const SLAB_OBJECTS: usize = 8;
+
+// Empty slabs a cache holds on to before it gives pages back, so an
+// allocation and a free in a row do not go to the page allocator each
+// time.
+const MAX_EMPTY_SLABS: usize = 1;
+
+// Sits at the start of every slab.
+struct Slab {
+    // Links of the partial or full list.
+    prev: *mut Slab,
+    next: *mut Slab,
+    free: *mut FreeObject,
+    inuse: usize,
+    class: usize,
+}
+
+struct FreeObject {
+    next: *mut FreeObject,
 }
 
-impl AllocListFlags {
-    pub fn val(self) -> usize {
-        self as usize
+#[derive(Clone, Copy)]
+struct Cache {
+    // Slabs with at least one free object.
+    partial: *mut Slab,
+    full: *mut Slab,
+    slabs: usize,
+    // Slabs with no object in use. They are on the partial list, too.
+    empty: usize,
+    inuse: usize,
+}
+
+const EMPTY_CACHE: Cache = Cache {
+    partial: null_mut(),
+    full: null_mut(),
+    slabs: 0,
+    empty: 0,
+    inuse: 0,
+};
+
+static mut CACHES: [Cache; CLASSES] = [EMPTY_CACHE; CLASSES];
+// Guards the caches and the slabs in them. It is never held while calling
+// vmalloc() or vfree(), since the vmalloc region's lock comes first.
+static KMEM_LOCK: IrqLock<()> = IrqLock::new(());
+static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+
+const fn class_size(class: usize) -> usize {
+    1 << (class + MIN_CLASS_ORDER)
+}
+
+// The smallest class that fits size bytes.
+fn class_of(size: usize) -> usize {
+    let mut class = 0;
+    while class_size(class) < size {
+        class += 1;
     }
+    class
+}
+
+fn slab_pages(class: usize) -> usize {
+    (class_size(class) * SLAB_OBJECTS / PAGE_SIZE).max(1)
 }
 
-struct AllocList {
-    pub flags_size: usize,
+// Offset of the first object in a slab. Objects are aligned to their size,
+// so the bigger classes lose the whole first object to the header.
+fn first_object(class: usize) -> usize {
+    align_val(size_of::<Slab>(), class + MIN_CLASS_ORDER)
 }
 
-impl AllocList {
-    pub fn is_taken(&self) -> bool {
-        self.flags_size & AllocListFlags::Taken.val() != 0
+fn objects_per_slab(class: usize) -> usize {
+    (slab_pages(class) * PAGE_SIZE - first_object(class)) / class_size(class)
+}
+
+unsafe fn slab_push(list: &mut *mut Slab, slab: *mut Slab) {
+    (*slab).prev = null_mut();
+    (*slab).next = *list;
+    if !list.is_null() {
+        (**list).prev = slab;
     }
+    *list = slab;
+}
 
-    pub fn is_free(&self) -> bool {
-        !self.is_taken()
+unsafe fn slab_remove(list: &mut *mut Slab, slab: *mut Slab) {
+    let (prev, next) = ((*slab).prev, (*slab).next);
+    if prev.is_null() {
+        *list = next;
+    } else {
+        (*prev).next = next;
     }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+}
 
-    pub fn set_taken(&mut self) {
-        self.flags_size |= AllocListFlags::Taken.val();
+// Add a fresh slab to the cache of class. Returns false if the page
+// allocator has nothing for us. This and the two below need KMEM_LOCK.
+unsafe fn grow(class: usize) -> bool {
+    let slab = alloc(slab_pages(class)) as *mut Slab;
+    if slab.is_null() {
+        return false;
     }
+    let size = class_size(class);
+    let first = (slab as *mut u8).add(first_object(class));
+    let mut free = null_mut();
+    for i in (0..objects_per_slab(class)).rev() {
+        let obj = first.add(i * size) as *mut FreeObject;
+        (*obj).next = free;
+        free = obj;
+        if DEBUG {
+            poison(obj as *mut u8, size);
+        }
+    }
+    slab.write(Slab {
+        prev: null_mut(),
+        next: null_mut(),
+        free,
+        inuse: 0,
+        class,
+    });
+    let cache = &mut CACHES[class];
+    slab_push(&mut cache.partial, slab);
+    cache.slabs += 1;
+    cache.empty += 1;
+    true
+}
 
-    pub fn set_free(&mut self) {
-        self.flags_size &= !AllocListFlags::Taken.val();
+unsafe fn cache_alloc(class: usize) -> *mut u8 {
+    if CACHES[class].partial.is_null() && !grow(class) {
+        return null_mut();
+    }
+    let cache = &mut CACHES[class];
+    let slab = cache.partial;
+    if (*slab).inuse == 0 {
+        cache.empty -= 1;
+    }
+    let obj = (*slab).free;
+    if DEBUG && !poisoned(obj as *const u8, class_size(class)) {
+        panic!("kmalloc: {}", HeapError::UseAfterFree(obj as usize));
+    }
+    (*slab).free = (*obj).next;
+    (*slab).inuse += 1;
+    cache.inuse += 1;
+    if (*slab).free.is_null() {
+        slab_remove(&mut cache.partial, slab);
+        slab_push(&mut cache.full, slab);
     }
+    obj as *mut u8
+}
 
-    pub fn set_size(&mut self, sz: usize) {
-        let k = self.is_taken();
-        self.flags_size = sz & !AllocListFlags::Taken.val();
-        if k {
-            self.flags_size |= AllocListFlags::Taken.val();
+unsafe fn cache_free(slab: *mut Slab, ptr: *mut u8) {
+    let cache = &mut CACHES[(*slab).class];
+    let obj = ptr as *mut FreeObject;
+    if (*slab).free.is_null() {
+        slab_remove(&mut cache.full, slab);
+        slab_push(&mut cache.partial, slab);
+    }
+    (*obj).next = (*slab).free;
+    (*slab).free = obj;
+    if DEBUG {
+        poison(ptr, class_size((*slab).class));
+    }
+    (*slab).inuse -= 1;
+    cache.inuse -= 1;
+    if (*slab).inuse == 0 {
+        if cache.empty < MAX_EMPTY_SLABS {
+            cache.empty += 1;
+        } else {
+            slab_remove(&mut cache.partial, slab);
+            cache.slabs -= 1;
+            dealloc(slab as *mut u8);
         }
     }
+}
+
+#[derive(Clone, Copy)]
+struct Magazine {
+    count: usize,
+    objects: [*mut u8; MAGAZINE_SIZE],
+}
+
+const MAGAZINE_SIZE: usize = 16;
+const MAGAZINE_BATCH: usize = 8;
+
+const EMPTY_MAGAZINE: Magazine = Magazine {
+    count: 0,
+    objects: [null_mut(); MAGAZINE_SIZE],
+};
+
+static mut MAGAZINES: [[Magazine; CLASSES]; cpu::KERNEL_TRAP_FRAME_COUNT] =
+    [[EMPTY_MAGAZINE; CLASSES]; cpu::KERNEL_TRAP_FRAME_COUNT];
+
+// Harts that shrink() asked to empty their magazines, one bit each. Only a
+// hart itself may touch its magazines, so it does that the next time it
+// gets to them.
+static SHRINK_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+// This hart's magazines, emptied first if shrink() asked for it.
+// Interrupts have to be off.
+unsafe fn hart_magazines() -> &'static mut [Magazine; CLASSES] {
+    let hart = cpu::hart_id();
+    let magazines = &mut MAGAZINES[hart];
+    if SHRINK_REQUESTS.load(Ordering::Relaxed) & 1 << hart != 0 {
+        SHRINK_REQUESTS.fetch_and(!(1 << hart), Ordering::Relaxed);
+        flush_magazines(magazines);
+    }
+    magazines
+}
 
-    pub fn get_size(&self) -> usize {
-        self.flags_size & !AllocListFlags::Taken.val()
+// Give everything in the magazines back to the slabs.
+unsafe fn flush_magazines(magazines: &mut [Magazine; CLASSES]) {
+    for magazine in magazines.iter_mut() {
+        let count = magazine.count;
+        magazine_flush(magazine, count);
     }
 }
 
-// This is the head of the allocation. We start here when
-// we search for a free memory location.
-static mut KMEM_HEAD: *mut AllocList = null_mut();
-// In the future, we will have on-demand pages
-// so, we need to keep track of our memory footprint to
-// see if we actually need to allocate more.
-static mut KMEM_ALLOC: usize = 0;
-static mut KMEM_PAGE_TABLE: *mut Table = null_mut();
+// Take an object of class from this hart's magazine. With kmem-debug, the
+// magazines are left out, so that every object goes through the checks in
+// the cache.
+unsafe fn magazine_alloc(class: usize) -> *mut u8 {
+    if DEBUG {
+        let _lock = KMEM_LOCK.lock();
+        return cache_alloc(class);
+    }
+    let on = cpu::interrupts_disable();
+    let magazine = &mut hart_magazines()[class];
+    if magazine.count == 0 {
+        let _lock = KMEM_LOCK.lock();
+        while magazine.count < MAGAZINE_BATCH {
+            let obj = cache_alloc(class);
+            if obj.is_null() {
+                break;
+            }
+            magazine.objects[magazine.count] = obj;
+            magazine.count += 1;
+        }
+    }
+    let obj = if magazine.count > 0 {
+        magazine.count -= 1;
+        magazine.objects[magazine.count]
+    } else {
+        null_mut()
+    };
+    cpu::interrupts_restore(on);
+    obj
+}
 
-pub fn get_head() -> *mut u8 {
-    unsafe { KMEM_HEAD as *mut u8 }
+unsafe fn magazine_free(class: usize, ptr: *mut u8) {
+    if DEBUG {
+        let slab = slab_of(ptr);
+        let _lock = KMEM_LOCK.lock();
+        cache_free(slab, ptr);
+        return;
+    }
+    let on = cpu::interrupts_disable();
+    let magazine = &mut hart_magazines()[class];
+    if magazine.count == MAGAZINE_SIZE {
+        magazine_flush(magazine, MAGAZINE_BATCH);
+    }
+    magazine.objects[magazine.count] = ptr;
+    magazine.count += 1;
+    cpu::interrupts_restore(on);
 }
 
-pub fn get_page_table() -> *mut Table {
-    unsafe { KMEM_PAGE_TABLE as *mut Table }
+// Give the count objects that have been in the magazine longest back to
+// their slabs.
+unsafe fn magazine_flush(magazine: &mut Magazine, count: usize) {
+    let count = count.min(magazine.count);
+    let _lock = KMEM_LOCK.lock();
+    for i in 0..count {
+        let obj = magazine.objects[i];
+        cache_free(slab_of(obj), obj);
+    }
+    magazine.objects.copy_within(count..magazine.count, 0);
+    magazine.count -= count;
 }
 
-pub fn get_num_allocations() -> usize {
-    unsafe { KMEM_ALLOC }
+unsafe fn slab_of(obj: *mut u8) -> *mut Slab {
+    live_allocation_start(obj as usize).unwrap() as *mut Slab
+}
+
+pub fn get_page_table() -> *mut Table {
+    unsafe { KMEM_PAGE_TABLE as *mut Table }
 }
 
 pub fn init() {
     unsafe {
-        let k_alloc = zalloc(64);
-        assert!(!k_alloc.is_null());
-        KMEM_ALLOC = 64;
-        KMEM_HEAD = k_alloc as *mut AllocList;
-        (*KMEM_HEAD).set_free();
-        (*KMEM_HEAD).set_size(KMEM_ALLOC * PAGE_SIZE);
+        CACHES = [EMPTY_CACHE; CLASSES];
         KMEM_PAGE_TABLE = zalloc(1) as *mut Table;
+        assert!(!KMEM_PAGE_TABLE.is_null());
     }
 }
 
 /// Allocate sub-page level allocation based on bytes and zero the memory
+#[track_caller]
 pub fn kzmalloc(sz: usize) -> *mut u8 {
-    let size = align_val(sz, 3);
-    let ret = kmalloc(size);
+    let ret = kmalloc(sz);
 
     if !ret.is_null() {
-        for i in 0..size {
-            unsafe {
-                (*ret.add(i)) = 0;
-            }
+        unsafe {
+            ret.write_bytes(0, sz);
         }
     }
     ret
 }
 
+/// Allocate sz bytes. Up to MAX_SLAB_SIZE, this is an object of the
+/// smallest size class that fits, otherwise whole pages.
+#[track_caller]
 pub fn kmalloc(sz: usize) -> *mut u8 {
+    kmalloc_aligned(sz, 1)
+}
+
+/// Allocate sz bytes at a multiple of align, which has to be a power of
+/// two.
+#[track_caller]
+pub fn kmalloc_aligned(sz: usize, align: usize) -> *mut u8 {
+    kmalloc_at(sz, align, Location::caller())
+}
+
+// kmalloc_aligned() for a caller at location.
+fn kmalloc_at(sz: usize, align: usize, location: &'static Location<'static>) -> *mut u8 {
+    assert!(
+        align.is_power_of_two(),
+        "Alignment {} is not a power of two",
+        align
+    );
+    let ptr = alloc_raw(sz + EXTRA, align);
+    if !ptr.is_null() {
+        unsafe {
+            finish(ptr, sz, location);
+        }
+    }
+    ptr
+}
+
+// About how many pages an allocation of sz bytes needs, for oom::retry().
+fn pages_for(sz: usize) -> usize {
+    (align_val(sz, PAGE_ORDER) / PAGE_SIZE).max(1)
+}
+
+/// Like kmalloc(), but if there is no memory, reclaim some and try again
+/// (without killing anybody, see oom.rs), and only then fail. kmalloc()
+/// itself never reclaims, which is what the reclaim path needs.
+#[track_caller]
+pub fn try_kmalloc(sz: usize) -> Result<NonNull<u8>, AllocError> {
+    try_kmalloc_aligned(sz, 1)
+}
+
+/// try_kmalloc() at a multiple of align.
+#[track_caller]
+pub fn try_kmalloc_aligned(sz: usize, align: usize) -> Result<NonNull<u8>, AllocError> {
+    let location = Location::caller();
+    let ptr = oom::retry(Reclaim::NoKill, pages_for(sz), || {
+        kmalloc_at(sz, align, location)
+    });
+    NonNull::new(ptr).ok_or(AllocError)
+}
+
+/// Move value into a Box, like Box::try_new(). That one goes through the
+/// global allocator, which kills an address space before it fails; this
+/// one fails first.
+#[track_caller]
+pub fn try_box<T>(value: T) -> Result<Box<T>, AllocError> {
+    let layout = Layout::new::<T>();
+    if layout.size() == 0 {
+        // Nothing to allocate.
+        return Ok(Box::new(value));
+    }
+    let ptr = try_kmalloc_aligned(layout.size(), layout.align())?.as_ptr() as *mut T;
     unsafe {
-        let size = align_val(sz, 3) + size_of::<AllocList>();
-        let mut head = KMEM_HEAD;
-
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-        while head < tail {
-            if (*head).is_free() && size <= (*head).get_size() {
-                let chunk_size = (*head).get_size();
-                let rem = chunk_size - size;
-                (*head).set_taken();
-                if rem > size_of::<AllocList>() {
-                    let next = (head as *mut u8).add(size) as *mut AllocList;
-                    (*next).set_free();
-                    (*next).set_size(rem);
-                    (*head).set_size(size);
-                } else {
-                    // If we get here, take the entire chunk
-                    (*head).set_size(chunk_size);
-                }
-                return head.add(1) as *mut u8;
-            } else {
-                // If we get here, what we saw wasn't free
-                // chunk, move on to the next.
-                head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+        ptr.write(value);
+        Ok(Box::from_raw(ptr))
+    }
+}
+
+// The red zone and the tag of a fresh allocation of sz bytes at ptr, if
+// they are built in.
+unsafe fn finish(ptr: *mut u8, sz: usize, location: &'static Location<'static>) {
+    if DEBUG {
+        arm(ptr, sz);
+    }
+    if TAGS {
+        tag(ptr, sz, location, clint::mtime());
+    }
+}
+
+// The allocation itself, without a red zone or a tag.
+fn alloc_raw(sz: usize, align: usize) -> *mut u8 {
+    let need = sz.max(align);
+    if need <= MAX_SLAB_SIZE {
+        return unsafe { magazine_alloc(class_of(need)) };
+    }
+    let pages = (align_val(sz, PAGE_ORDER) / PAGE_SIZE).max(1);
+    let ptr = alloc_align(pages, align);
+    if !ptr.is_null() || align > PAGE_SIZE {
+        return ptr;
+    }
+    // Memory is too fragmented for a run this long, or it is longer than
+    // the page allocator hands out at all. vmalloc() areas are only page
+    // aligned, which is why this is not an option above.
+    vmalloc(sz)
+}
+
+/// Number of bytes that can be used at ptr, which kmalloc() returned. This
+/// is at least as much as was asked for, and exactly that with kmem-debug.
+///
+/// # Safety
+/// ptr must be live memory from kmalloc(), since its red zone is read.
+pub unsafe fn ksize(ptr: *const u8) -> usize {
+    if DEBUG {
+        verify(ptr, raw_size(ptr)).unwrap_or_else(|err| panic!("ksize: {}", err))
+    } else {
+        raw_size(ptr) - TAG_SIZE
+    }
+}
+
+// Size of the allocation at ptr, including the red zone and the tag.
+unsafe fn raw_size(ptr: *const u8) -> usize {
+    if (VMALLOC_START..VMALLOC_END).contains(&(ptr as usize)) {
+        return vmalloc::size(ptr)
+            .unwrap_or_else(|| panic!("ksize of 0x{:x}, which is not allocated", ptr as usize));
+    }
+    let start = live_allocation_start(ptr as usize)
+        .unwrap_or_else(|| panic!("ksize of 0x{:x}, which is not allocated", ptr as usize));
+    if start == ptr as usize {
+        allocation_pages(start) * PAGE_SIZE
+    } else {
+        class_size((*(start as *const Slab)).class)
+    }
+}
+
+/// Change the size of what kmalloc_aligned() returned to sz bytes, keeping
+/// what is in it up to the smaller of the two sizes. It stays where it is
+/// if it fits, or if it is a page run and the pages behind it are free;
+/// otherwise it moves to a new allocation at a multiple of align. Returns
+/// null, and leaves ptr alone, if there is no memory for that.
+///
+/// # Safety
+/// ptr must be null or live memory from kmalloc(). Once this returned
+/// anything but null, ptr must not be used any more.
+#[track_caller]
+pub unsafe fn krealloc(ptr: *mut u8, sz: usize, align: usize) -> *mut u8 {
+    if ptr.is_null() {
+        return kmalloc_aligned(sz, align);
+    }
+    let old = ksize(ptr);
+    let raw = raw_size(ptr);
+    // The tag is at the end, which is about to move or be freed.
+    let tagged = if TAGS { Some(untag(ptr)) } else { None };
+    let total = sz + EXTRA;
+    let need = total.max(align);
+    let in_place = if (VMALLOC_START..VMALLOC_END).contains(&(ptr as usize)) {
+        // Only if the area keeps its size, since it cannot be resized.
+        need > MAX_SLAB_SIZE && align_val(total, PAGE_ORDER) == raw
+    } else if live_allocation_start(ptr as usize) == Some(ptr as usize) {
+        need > MAX_SLAB_SIZE
+            && resize(
+                ptr as usize,
+                (align_val(total, PAGE_ORDER) / PAGE_SIZE).max(1),
+            )
+    } else {
+        // Only within the same class, so shrinking a lot still moves it
+        // to a smaller one.
+        need <= MAX_SLAB_SIZE && class_size(class_of(need)) == raw
+    };
+    if in_place {
+        finish(ptr, sz, Location::caller());
+        return ptr;
+    }
+    if let Some((location, time, size)) = tagged {
+        tag(ptr, size, location, time);
+    }
+    let new = kmalloc_aligned(sz, align);
+    if !new.is_null() {
+        core::ptr::copy_nonoverlapping(ptr, new, old.min(sz));
+        kfree(ptr);
+    }
+    new
+}
+
+/// Free what kmalloc() returned.
+///
+/// # Safety
+/// ptr must be null or live memory from kmalloc(), and must not be used
+/// afterwards. Its tag and red zone are read, so a pointer that kmalloc()
+/// did not hand out is only caught as long as that memory is mapped.
+pub unsafe fn kfree(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+    if DEBUG {
+        check_free(ptr).unwrap_or_else(|err| panic!("kfree: {}", err));
+    }
+    if TAGS {
+        untag(ptr);
+    }
+    if (VMALLOC_START..VMALLOC_END).contains(&(ptr as usize)) {
+        vfree(ptr);
+        return;
+    }
+    let start = live_allocation_start(ptr as usize)
+        .unwrap_or_else(|| panic!("kfree of 0x{:x}, which is not allocated", ptr as usize));
+    if start == ptr as usize {
+        dealloc(ptr);
+    } else {
+        magazine_free((*(start as *mut Slab)).class, ptr);
+    }
+}
+
+/// Give the empty slabs that the caches hold on to back to the page
+/// allocator, after emptying this hart's magazines into them. The other
+/// harts are asked to empty theirs the next time they allocate or free a
+/// small object, so what they hold comes back with a later call. Returns
+/// the number of pages freed.
+pub fn shrink() -> usize {
+    let hart = cpu::hart_id();
+    let all = (1 << cpu::KERNEL_TRAP_FRAME_COUNT) - 1;
+    SHRINK_REQUESTS.fetch_or(all & !(1 << hart), Ordering::Relaxed);
+    let on = cpu::interrupts_disable();
+    unsafe {
+        flush_magazines(&mut MAGAZINES[hart]);
+    }
+    cpu::interrupts_restore(on);
+    (0..CLASSES).map(shrink_class).sum()
+}
+
+fn shrink_class(class: usize) -> usize {
+    let mut pages = 0;
+    unsafe {
+        let _lock = KMEM_LOCK.lock();
+        let cache = &mut CACHES[class];
+        let mut slab = cache.partial;
+        while !slab.is_null() {
+            let next = (*slab).next;
+            if (*slab).inuse == 0 {
+                slab_remove(&mut cache.partial, slab);
+                cache.slabs -= 1;
+                cache.empty -= 1;
+                dealloc(slab as *mut u8);
+                pages += slab_pages(class);
             }
+            slab = next;
         }
-        // If we get here, we didn't find any free chunks--i.e. there isn't
-        // enough memory for this.
-        // TODO: Add on-demand page allocation.
-        null_mut()
     }
+    pages
 }
 
-// Free a sub-page level allocation
-pub fn kfree(ptr: *mut u8) {
-    unsafe {
-        if !ptr.is_null() {
-            let p = (ptr as *mut AllocList).offset(-1);
-            if (*p).is_taken() {
-                (*p).set_free();
+// ///////////////////////////////////
+// / DEBUG ALLOCATOR
+// ///////////////////////////////////
+// Built with the kmem-debug feature, every allocation gets REDZONE more
+// bytes than it asked for. Everything behind the caller's part is filled
+// with CANARY, except for the last word before the tag (if there is one),
+// which holds the size that was asked for. kfree() checks that the red
+// zone is intact, which catches writes past the end of an allocation;
+// writes in front of a slab object land in the red zone of the one before
+// it. Free slab objects are filled with POISON behind their free list
+// link, and kmalloc() checks that nobody wrote to them in the meantime.
+
+const DEBUG: bool = cfg!(feature = "kmem-debug");
+// A canary word and the size.
+const REDZONE: usize = 2 * size_of::<usize>();
+const CANARY: u8 = 0xa5;
+const POISON: u8 = 0x6b;
+// Makes a size word stand out from the canary and from zeroes.
+const SIZE_MAGIC: usize = 0x6b6d_656d << 32;
+// The most objects a slab can hold, which is the smallest class in a page.
+const MAX_OBJECTS: usize = PAGE_SIZE >> MIN_CLASS_ORDER;
+
+// What every allocation takes on top of what was asked for.
+const EXTRA: usize = if DEBUG { REDZONE } else { 0 } + TAG_SIZE;
+
+/// Heap corruption found by kfree() or check(), with the address of the
+/// allocation it was found in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HeapError {
+    /// Something wrote past the end of the allocation.
+    Overrun(usize),
+    /// The free object was written to after it was freed.
+    UseAfterFree(usize),
+    /// The object was freed while it was free already.
+    DoubleFree(usize),
+    /// The address is not the start of anything that is allocated. Page
+    /// runs that are freed twice end up here, too.
+    InvalidFree(usize),
+    /// The free list or the counts of the slab at the address are broken.
+    BadSlab(usize),
+}
+
+impl core::fmt::Display for HeapError {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        match *self {
+            HeapError::Overrun(addr) => write!(f, "overrun of the allocation at 0x{:x}", addr),
+            HeapError::UseAfterFree(addr) => {
+                write!(f, "0x{:x} was written to after it was freed", addr)
             }
-            // After we free, see if we can combine adjacent free
-            // spots to see if we can reduce fragmentation.
-            coalesce();
+            HeapError::DoubleFree(addr) => write!(f, "double free of 0x{:x}", addr),
+            HeapError::InvalidFree(addr) => write!(f, "0x{:x} is not allocated", addr),
+            HeapError::BadSlab(addr) => write!(f, "the slab at 0x{:x} is corrupted", addr),
         }
     }
 }
 
-// Merge smaller chunks into a bigger chunk
-pub fn coalesce() {
-    unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-
-        while head < tail {
-            let next = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
-            if (*head).get_size() == 0 {
-                // If this happens, then we have a bad heap
-                // (double free or something). However, that
-                // will cause an infinite loop since the next
-                // pointer will never move beyond the current
-                // location.
-                break;
-            } else if next >= tail {
-                // We calculated the next by using the size
-                // given as get_size(), however this could push
-                // us past the tail. In that case, the size is
-                // wrong, hence we break and stop doing what we
-                // need to do.
-                break;
-            } else if (*head).is_free() && (*next).is_free() {
-                (*head).set_size((*head).get_size() + (*next).get_size());
+// Put a red zone behind the first sz bytes of the allocation at ptr.
+unsafe fn arm(ptr: *mut u8, sz: usize) {
+    let raw = raw_size(ptr);
+    let size_word = raw - TAG_SIZE - size_of::<usize>();
+    ptr.add(sz).write_bytes(CANARY, size_word - sz);
+    (ptr.add(size_word) as *mut usize).write(sz ^ SIZE_MAGIC);
+}
+
+// Check the red zone of the raw bytes long allocation at ptr, and return
+// the size that was asked for.
+unsafe fn verify(ptr: *const u8, raw: usize) -> Result<usize, HeapError> {
+    let size_word = raw - TAG_SIZE - size_of::<usize>();
+    let sz = *(ptr.add(size_word) as *const usize) ^ SIZE_MAGIC;
+    if sz > raw - EXTRA || (sz..size_word).any(|i| *ptr.add(i) != CANARY) {
+        return Err(HeapError::Overrun(ptr as usize));
+    }
+    Ok(sz)
+}
+
+unsafe fn poison(obj: *mut u8, size: usize) {
+    let link = size_of::<FreeObject>();
+    obj.add(link).write_bytes(POISON, size - link);
+}
+
+unsafe fn poisoned(obj: *const u8, size: usize) -> bool {
+    (size_of::<FreeObject>()..size).all(|i| *obj.add(i) == POISON)
+}
+
+// Everything kfree() can find out about ptr before it frees it.
+unsafe fn check_free(ptr: *mut u8) -> Result<(), HeapError> {
+    let addr = ptr as usize;
+    let raw = if (VMALLOC_START..VMALLOC_END).contains(&addr) {
+        vmalloc::size(ptr).ok_or(HeapError::InvalidFree(addr))?
+    } else {
+        let start = allocation_start(addr).ok_or(HeapError::InvalidFree(addr))?;
+        if start == addr {
+            allocation_pages(addr) * PAGE_SIZE
+        } else {
+            let slab = start as *mut Slab;
+            let size = class_size((*slab).class);
+            let first = start + first_object((*slab).class);
+            if addr < first || (addr - first) % size != 0 {
+                return Err(HeapError::InvalidFree(addr));
             }
+            let _lock = KMEM_LOCK.lock();
+            let mut obj = (*slab).free;
+            while !obj.is_null() {
+                if obj as usize == addr {
+                    return Err(HeapError::DoubleFree(addr));
+                }
+                obj = (*obj).next;
+            }
+            size
+        }
+    };
+    verify(ptr, raw).map(|_| ())
+}
 
-            // If we get here, we might've moved. Recalculate new head.
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+unsafe fn check_slab(slab: *mut Slab, class: usize) -> Result<(), HeapError> {
+    let bad = Err(HeapError::BadSlab(slab as usize));
+    if (*slab).class != class {
+        return bad;
+    }
+    let size = class_size(class);
+    let first = slab as usize + first_object(class);
+    let count = objects_per_slab(class);
+    // One bit for every free object, which also catches a loop in the
+    // free list.
+    let mut free = [0u64; MAX_OBJECTS / 64];
+    let mut free_count = 0;
+    let mut obj = (*slab).free as usize;
+    while obj != 0 {
+        if obj < first || (obj - first) % size != 0 || (obj - first) / size >= count {
+            return bad;
+        }
+        let i = (obj - first) / size;
+        if free[i / 64] & 1 << (i % 64) != 0 {
+            return bad;
+        }
+        free[i / 64] |= 1 << (i % 64);
+        free_count += 1;
+        if DEBUG && !poisoned(obj as *const u8, size) {
+            return Err(HeapError::UseAfterFree(obj));
+        }
+        obj = (*(obj as *const FreeObject)).next as usize;
+    }
+    if free_count + (*slab).inuse != count {
+        return bad;
+    }
+    if DEBUG {
+        for i in (0..count).filter(|i| free[i / 64] & 1 << (i % 64) == 0) {
+            verify((first + i * size) as *const u8, size)?;
         }
     }
+    Ok(())
 }
 
-// For debugging purposes, print the kmem table
+/// Walk every slab and check its free list and counts, and with kmem-debug
+/// also the red zone of every object in use and the poison of every free
+/// one. Returns the first corruption it finds. Page runs and vmalloc areas
+/// are only checked when they are freed.
+pub fn check() -> Result<(), HeapError> {
+    let _lock = KMEM_LOCK.lock();
+    for (class, cache) in unsafe { CACHES.iter().enumerate() } {
+        unsafe {
+            for &list in [cache.partial, cache.full].iter() {
+                let mut slab = list;
+                while !slab.is_null() {
+                    check_slab(slab, class)?;
+                    slab = (*slab).next;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// ///////////////////////////////////
+// / ALLOCATION TAGS
+// ///////////////////////////////////
+// Built with the kmem-tags feature, every allocation ends in a Tag that
+// says where it was made and when, and all tags are linked together, so
+// print_allocations() can add up what is live by call site. The caller is
+// found through #[track_caller], which cannot see through liballoc:
+// everything that comes in through the global allocator shows up under
+// OsGlobalAlloc::alloc().
+
+const TAGS: bool = cfg!(feature = "kmem-tags");
+const TAG_SIZE: usize = if TAGS { size_of::<Tag>() } else { 0 };
+
+struct Tag {
+    prev: *mut Tag,
+    next: *mut Tag,
+    location: &'static Location<'static>,
+    // mtime when the allocation was made.
+    time: u64,
+    // The size that was asked for.
+    size: usize,
+}
+
+// The newest tag. TAG_LOCK guards the list, and is never held while
+// taking another lock.
+static mut TAGS_HEAD: *mut Tag = null_mut();
+static TAG_LOCK: IrqLock<()> = IrqLock::new(());
+
+// How many call sites print_allocations() tells apart.
+const MAX_SITES: usize = 64;
+
+unsafe fn tag_of(ptr: *const u8) -> *mut Tag {
+    ptr.add(raw_size(ptr) - TAG_SIZE) as *mut Tag
+}
+
+unsafe fn tag(ptr: *mut u8, size: usize, location: &'static Location<'static>, time: u64) {
+    let tag = tag_of(ptr);
+    let _lock = TAG_LOCK.lock();
+    tag.write(Tag {
+        prev: null_mut(),
+        next: TAGS_HEAD,
+        location,
+        time,
+        size,
+    });
+    if !TAGS_HEAD.is_null() {
+        (*TAGS_HEAD).prev = tag;
+    }
+    TAGS_HEAD = tag;
+}
+
+// Take the tag of ptr off the list, and return where and when it was made
+// and how big.
+unsafe fn untag(ptr: *const u8) -> (&'static Location<'static>, u64, usize) {
+    let tag = tag_of(ptr);
+    let _lock = TAG_LOCK.lock();
+    let (prev, next) = ((*tag).prev, (*tag).next);
+    if prev.is_null() {
+        TAGS_HEAD = next;
+    } else {
+        (*prev).next = next;
+    }
+    if !next.is_null() {
+        (*next).prev = prev;
+    }
+    ((*tag).location, (*tag).time, (*tag).size)
+}
+
+/// Print the allocations that were made at or after since (an mtime value)
+/// and are still live, added up by call site with the most bytes first.
+/// Whatever a test leaves behind after it is done is a likely leak. This
+/// needs the kmem-tags feature.
+pub fn print_allocations(since: u64) {
+    if !TAGS {
+        println!("kmem: allocation tags need the kmem-tags feature");
+        return;
+    }
+    // Count, bytes and the oldest time for every site. Allocating takes
+    // TAG_LOCK, so the walk cannot, and the sites live on the stack.
+    // Whatever comes from sites beyond the first MAX_SITES only shows up
+    // in the total.
+    let mut sites = [None; MAX_SITES];
+    let mut used = 0;
+    let (mut count, mut bytes) = (0, 0);
+    {
+        let _lock = TAG_LOCK.lock();
+        unsafe {
+            let mut tag = TAGS_HEAD;
+            while !tag.is_null() {
+                if (*tag).time >= since {
+                    let location = (*tag).location;
+                    let i = sites[..used]
+                        .iter()
+                        .position(|site| matches!(site, Some((l, _)) if *l == location))
+                        .unwrap_or(used);
+                    if i == used && used < MAX_SITES {
+                        sites[used] = Some((location, (0, 0, u64::MAX)));
+                        used += 1;
+                    }
+                    if let Some(Some((_, site))) = sites.get_mut(i) {
+                        site.0 += 1;
+                        site.1 += (*tag).size;
+                        site.2 = site.2.min((*tag).time);
+                    }
+                    count += 1;
+                    bytes += (*tag).size;
+                }
+                tag = (*tag).next;
+            }
+        }
+    }
+    let sites = &mut sites[..used];
+    sites.sort_unstable_by_key(|site| Reverse(site.map(|(_, (_, bytes, _))| bytes)));
+    println!("\n============== LIVE ALLOCATIONS ===============");
+    for &(location, (site_count, site_bytes, oldest)) in sites.iter().flatten() {
+        println!(
+            "{:>10} B in {:>6} allocation(s), oldest at {:>12}: {}",
+            site_bytes, site_count, oldest, location
+        );
+    }
+    println!("Total: {} B in {} allocation(s)", bytes, count);
+    println!("================================================\n");
+}
+
+// For debugging purposes, print the slab caches
 pub fn print_table() {
     println!("\n================== KMEM TABLE ==================");
-    unsafe {
-        let mut head = KMEM_HEAD;
-        let tail = (KMEM_HEAD as *mut u8).add(KMEM_ALLOC * PAGE_SIZE) as *mut AllocList;
-
-        while head < tail {
-            println!(
-                "{:p}: Length = {:<10} Taken = {}",
-                head,
-                (*head).get_size(),
-                (*head).is_taken()
-            );
-
-            head = (head as *mut u8).add((*head).get_size()) as *mut AllocList;
+    let caches = {
+        let _lock = KMEM_LOCK.lock();
+        unsafe { CACHES }
+    };
+    let mut pages = 0;
+    for (class, cache) in caches.iter().enumerate() {
+        if cache.slabs == 0 {
+            continue;
         }
+        let cached: usize = unsafe { MAGAZINES.iter().map(|hart| hart[class].count).sum() };
+        println!(
+            "{:>5} B: {:>4} slab(s) {:>6} / {:<6} in use, {:>4} in magazines",
+            class_size(class),
+            cache.slabs,
+            cache.inuse - cached,
+            cache.slabs * objects_per_slab(class),
+            cached
+        );
+        pages += cache.slabs * slab_pages(class);
     }
+    println!("Slab pages: {}", pages);
     println!("================================================\n");
 }
 // ///////////////////////////////////
@@ -202,9 +943,11 @@ pub fn print_table() {
 
 // The global allocator allows us to use the data structures
 // in the core library, such as a linked list or B-tree.
-// We want to use these sparingly since we have a coarse-grained
-// allocator.
-use core::alloc::{GlobalAlloc, Layout};
+// Anything small comes out of the slab caches, so Box and Vec are
+// cheap, and a Vec that keeps growing past a page can often do so
+// without copying. Neither can cope with a failed allocation, so
+// before one fails, everything in oom.rs is tried.
+use core::alloc::{AllocError, GlobalAlloc, Layout};
 
 // The global allocator is a static constant to a global allocator
 // structure. We don't need any members because we're using this
@@ -213,17 +956,24 @@ struct OsGlobalAlloc;
 
 unsafe impl GlobalAlloc for OsGlobalAlloc {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
-        // We align to the next page size so that when
-        // we divide by PAGE_SIZE, we get exactly the number
-        // of pages necessary.
-        kzmalloc(layout.size())
+        // Nobody asked for zeroed memory here, alloc_zeroed() does
+        // that on top of this.
+        oom::retry(Reclaim::Kill, pages_for(layout.size()), || {
+            kmalloc_at(layout.size(), layout.align(), Location::caller())
+        })
     }
 
     unsafe fn dealloc(&self, ptr: *mut u8, _layout: Layout) {
-        // We ignore layout since our allocator uses ptr_start -> last
-        // to determine the span of an allocation.
+        // We ignore layout since kfree() can tell from ptr alone
+        // whether it is a slab object or pages of its own.
         kfree(ptr);
     }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        oom::retry(Reclaim::Kill, pages_for(new_size), || {
+            krealloc(ptr, new_size, layout.align())
+        })
+    }
 }
 
 #[global_allocator]
@@ -233,9 +983,10 @@ unsafe impl GlobalAlloc for OsGlobalAlloc {
 static GA: OsGlobalAlloc = OsGlobalAlloc {};
 
 #[alloc_error_handler]
-/// If for some reason alloc() in the global allocator gets null_mut(),
-/// then we come here. This is a divergent function, so we call panic to
-/// let the tester know what's going on.
+/// If alloc() in the global allocator still gets null_mut() after
+/// oom::retry() freed everything it could, then we come here. This is a
+/// divergent function, so we call panic to let the tester know what's
+/// going on.
 pub fn alloc_error(l: Layout) -> ! {
     panic!(
         "Allocator failed to allocate {} bytes with {}-byte alignment.",
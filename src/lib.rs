@@ -2,6 +2,8 @@
 #![feature(panic_info_message, global_asm, llvm_asm)]
 #![feature(asm, allocator_api, alloc_error_handler, const_raw_ptr_to_usize_cast)]
 
+extern crate alloc;
+
 // ///////////////////////////////////
 // / RUST MACROS
 // ///////////////////////////////////
@@ -9,10 +11,20 @@
 // ///////////////////////////////////
 // / RUST MODULES
 // ///////////////////////////////////
+pub mod asid;
 pub mod assembly;
+pub mod block;
+pub mod clint;
 pub mod cpu;
+pub mod fdt;
 pub mod kmem;
+pub mod lock;
+pub mod oom;
 pub mod page;
 pub mod plic;
+pub mod swap;
 pub mod trap;
 pub mod uart;
+pub mod virtio;
+pub mod vm;
+pub mod vmalloc;
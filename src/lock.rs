@@ -0,0 +1,64 @@
+// Spinlocks that keep interrupts off on this hart for as long as they are
+// held. Otherwise an interrupt handler that wants a lock its own hart
+// already holds would spin on it forever.
+//
+// Where one allocator lock is taken while another is held, the order is
+// vmalloc, then kmem, then page. The swap lock comes before page, too.
+
+use crate::cpu;
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use spin::{Mutex, MutexGuard};
+
+pub struct IrqLock<T> {
+    mutex: Mutex<T>,
+}
+
+/// Holds an IrqLock until it is dropped.
+pub struct IrqGuard<'a, T> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    // Whether interrupts were on before lock().
+    interrupts: bool,
+}
+
+impl<T> IrqLock<T> {
+    pub const fn new(value: T) -> IrqLock<T> {
+        IrqLock {
+            mutex: Mutex::new(value),
+        }
+    }
+
+    /// Turn interrupts off and spin until the lock is ours. Interrupts come
+    /// back on with the guard's drop if they were on before.
+    pub fn lock(&self) -> IrqGuard<T> {
+        let interrupts = cpu::interrupts_disable();
+        IrqGuard {
+            guard: ManuallyDrop::new(self.mutex.lock()),
+            interrupts,
+        }
+    }
+}
+
+impl<'a, T> Deref for IrqGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<'a, T> DerefMut for IrqGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<'a, T> Drop for IrqGuard<'a, T> {
+    fn drop(&mut self) {
+        // Unlock first, so no interrupt can come in while we still hold it.
+        unsafe {
+            ManuallyDrop::drop(&mut self.guard);
+        }
+        cpu::interrupts_restore(self.interrupts);
+    }
+}
@@ -2,11 +2,18 @@
 #![no_std]
 #![feature(panic_info_message, global_asm, llvm_asm, alloc_prelude)]
 
+use blog_os_riscv::asid;
+use blog_os_riscv::block;
+use blog_os_riscv::clint;
 use blog_os_riscv::cpu;
+use blog_os_riscv::fdt;
 use blog_os_riscv::kmem;
 use blog_os_riscv::page;
 use blog_os_riscv::plic;
-use blog_os_riscv::uart::Uart;
+use blog_os_riscv::swap;
+use blog_os_riscv::uart::{self, Uart};
+use blog_os_riscv::virtio;
+use blog_os_riscv::vmalloc;
 use blog_os_riscv::{print, println};
 
 #[macro_use]
@@ -59,49 +66,99 @@ extern "C" {
     static BSS_END: usize;
     static KERNEL_STACK_START: usize;
     static KERNEL_STACK_END: usize;
-    static HEAP_START: usize;
-    static HEAP_SIZE: usize;
     static mut KERNEL_TABLE: usize;
 }
 
-pub fn id_map_range(root: &mut page::Table, start: usize, end: usize, bits: i64) {
-    let mut memaddr = start & !(page::PAGE_SIZE - 1);
-    let num_kb_pages = (page::align_val(end, 12) - memaddr) / page::PAGE_SIZE;
-
-    // I named this num_kb_pages for future expansion when
-    // I decide to allow for GiB (2^30) and 2MiB (2 ^21) page
-    // sizes. However, the overlapping memory regions are causing
-    // nightmares.
-    for _ in 0..num_kb_pages {
-        page::map(root, memaddr, memaddr, bits, 0);
-        memaddr += 1 << 12;
-    }
-}
-
 // ///////////////////////////////////
 // / ENTRY POINT
 // ///////////////////////////////////
+// Map part of the kernel into root. If the page tables for that cannot be
+// allocated this early, there is nothing to fall back on.
+fn map_kernel(root: &mut page::Table, start: usize, end: usize, flags: page::PteFlags) {
+    page::map_kernel_range(root, start, end, flags)
+        .expect("Out of memory while mapping the kernel");
+}
+
 #[no_mangle]
-extern "C" fn kinit() {
-    // We created kinit, which runs in super-duper mode
-    // 3 called "machine mode".
-    // The job of kinit() is to get us into supervisor mode
-    // as soon as possible.
+extern "C" fn kinit(fdt_addr: usize, sv48: bool) {
+    // boot.S calls kinit in supervisor mode, running on a table that maps
+    // all of physical memory at page::PHYS_OFFSET. The job of kinit() is
+    // to build the kernel's real page table and switch to it.
     // Interrupts are disabled for the duration of kinit()
-    Uart::new(0x1000_0000).init();
-    page::init();
+
+    // The allocators keep per-hart caches, which they find through the
+    // trap frame in sscratch, so it has to be set before anything is
+    // allocated.
+    unsafe {
+        cpu::sscratch_write(&mut cpu::KERNEL_TRAP_FRAME[0] as *mut cpu::TrapFrame as usize);
+    }
+
+    // QEMU gives us the device tree blob in a1, and boot.S passes it on.
+    // Find the devices first, so the UART is where we think it is before
+    // we print anything. Without a device tree, the drivers keep the
+    // addresses of the QEMU virt machine.
+    if fdt_addr != 0 {
+        fdt::init(page::phys_to_virt(fdt_addr));
+    }
+    uart::probe();
+    clint::probe();
+    plic::probe();
+    virtio::probe();
+    Uart::new(uart::base_address()).init();
+    // Use Sv48 whenever the hart has it, since it gives user space 128 TiB
+    // instead of 256 GiB. All page tables are built for this mode, so it
+    // has to be decided before anything is mapped. boot.S tried it for us.
+    if sv48 {
+        page::set_paging_mode(cpu::SatpMode::Sv48);
+    } else {
+        page::set_paging_mode(cpu::SatpMode::Sv39);
+    }
+    // RAM ends where the memory node that holds the kernel says it does.
+    let kernel_phys = page::virt_to_phys(unsafe { TEXT_START });
+    let ram = fdt::get().and_then(|fdt| {
+        fdt.memory()
+            .find(|&(base, size)| kernel_phys >= base && kernel_phys - base < size)
+    });
+    page::init(ram.map(|(base, size)| base + size));
+    // QEMU puts the blob at the top of RAM, so keep the allocator away from
+    // it, and from whatever else the firmware wants left alone.
+    if let Some(fdt) = fdt::get() {
+        let fdt_phys = page::virt_to_phys(fdt.addr());
+        page::reserve(fdt_phys, fdt_phys + fdt.size());
+        for (base, size) in fdt.reservations() {
+            page::reserve(base, base + size);
+        }
+    }
     kmem::init();
+    vmalloc::init();
+    // The boot table maps the devices as well, so the disk can be set up
+    // right away. Its queues come from the DMA zone.
+    if block::init() {
+        swap::init();
+    }
 
     // Map heap allocations
     let root_ptr = kmem::get_page_table();
     let root_u = root_ptr as usize;
     let mut root = unsafe { root_ptr.as_mut().unwrap() };
-    let kheap_head = kmem::get_head() as usize;
-    let total_pages = kmem::get_num_allocations();
 
     println!();
     println!();
 
+    if let Some(fdt) = fdt::get() {
+        println!(
+            "FDT:    0x{:x} -> 0x{:x}, {} hart(s)",
+            fdt.addr(),
+            fdt.addr() + fdt.size(),
+            fdt.hart_count()
+        );
+        if fdt.hart_count() > cpu::KERNEL_TRAP_FRAME_COUNT {
+            println!(
+                "Only the first {} harts have a kernel trap frame.",
+                cpu::KERNEL_TRAP_FRAME_COUNT
+            );
+        }
+    }
     unsafe {
         println!("TEXT:   0x{:x} -> 0x{:x}", TEXT_START, TEXT_END);
         println!("RODATA: 0x{:x} -> 0x{:x}", RODATA_START, RODATA_END);
@@ -111,198 +168,127 @@ extern "C" fn kinit() {
             "STACK:  0x{:x} -> 0x{:x}",
             KERNEL_STACK_START, KERNEL_STACK_END
         );
-        println!(
-            "HEAP:   0x{:x} -> 0x{:x}",
-            kheap_head,
-            kheap_head + total_pages * 4096
-        );
     }
 
-    id_map_range(
+    // The direct map: all of RAM at PHYS_OFFSET. This covers the heap, the
+    // page descriptors and everything the page allocator hands out. Nothing
+    // is mapped in the lower half, which is left to user space.
+    let ram_start = ram.map_or(kernel_phys, |(base, _)| base);
+    page::map_range(
         &mut root,
-        kheap_head,
-        kheap_head + total_pages * 4096,
-        page::EntryBits::ReadWrite.val(),
-    );
+        page::phys_to_virt(ram_start),
+        ram_start,
+        page::memory_end() - ram_start,
+        page::PteFlags::READ_WRITE,
+    )
+    .expect("Out of memory while mapping RAM");
 
+    // The kernel image is inside the direct map, so these only narrow
+    // down the permissions of its sections. The linker script puts every
+    // section on pages of its own, so they do not have to share any.
     unsafe {
-        // Map heap descriptors
-        let num_pages = HEAP_SIZE / page::PAGE_SIZE;
-        id_map_range(
-            &mut root,
-            HEAP_START,
-            HEAP_START + num_pages,
-            page::EntryBits::ReadWrite.val(),
-        );
         // Map executable section
-        id_map_range(
+        map_kernel(
             &mut root,
             TEXT_START,
             TEXT_END,
-            page::EntryBits::ReadExecute.val(),
+            page::PteFlags::READ_EXECUTE,
         );
         // Map rodata section
-        // We put the ROdata section into the text section, so they can
-        // potentially overlap however, we only care that it's read only.
-        id_map_range(
-            &mut root,
-            RODATA_START,
-            RODATA_END,
-            page::EntryBits::ReadExecute.val(),
-        );
+        map_kernel(&mut root, RODATA_START, RODATA_END, page::PteFlags::READ);
         // Map data section
-        id_map_range(
-            &mut root,
-            DATA_START,
-            DATA_END,
-            page::EntryBits::ReadWrite.val(),
-        );
+        map_kernel(&mut root, DATA_START, DATA_END, page::PteFlags::READ_WRITE);
         // Map bss section
-        id_map_range(
-            &mut root,
-            BSS_START,
-            BSS_END,
-            page::EntryBits::ReadWrite.val(),
-        );
+        map_kernel(&mut root, BSS_START, BSS_END, page::PteFlags::READ_WRITE);
         // Map kernel stack
-        id_map_range(
+        map_kernel(
             &mut root,
             KERNEL_STACK_START,
             KERNEL_STACK_END,
-            page::EntryBits::ReadWrite.val(),
+            page::PteFlags::READ_WRITE,
         );
     }
 
-    // UART
-    page::map(
-        &mut root,
-        0x1000_0000,
-        0x1000_0000,
-        page::EntryBits::ReadWrite.val(),
-        0,
-    );
-
-    // CLINT
-    // -> MSIP
-    page::map(
-        &mut root,
-        0x0200_0000,
-        0x0200_0000,
-        page::EntryBits::ReadWrite.val(),
-        0,
-    );
-    // -> MTIMECMP
-    page::map(
-        &mut root,
-        0x0200_b000,
-        0x0200_b000,
-        page::EntryBits::ReadWrite.val(),
-        0,
-    );
-    // -> MTIME
-    page::map(
-        &mut root,
-        0x0200_c000,
-        0x0200_c000,
-        page::EntryBits::ReadWrite.val(),
-        0,
-    );
-    // PLIC
-    id_map_range(
-        &mut root,
-        0x0c00_0000,
-        0x0c00_2001,
-        page::EntryBits::ReadWrite.val(),
-    );
-    id_map_range(
-        &mut root,
-        0x0c20_0000,
-        0x0c20_8000,
-        page::EntryBits::ReadWrite.val(),
-    );
+    // The devices, with the ranges the device tree gave them, at the same
+    // offset as RAM.
+    let devices = [uart::mmio_range(), clint::mmio_range(), plic::mmio_range()];
+    for &(base, size) in devices.iter().chain(virtio::mmio_ranges()) {
+        map_kernel(
+            &mut root,
+            page::phys_to_virt(base),
+            page::phys_to_virt(base + size),
+            page::PteFlags::READ_WRITE,
+        );
+    }
     page::print_page_allocations();
+    page::print_mappings(root);
+    // satp still points at boot.S's table, so show what we are about to
+    // change by switching over.
+    let boot_root = page::phys_to_virt((cpu::satp_read() & 0xfff_ffff_ffff) * page::PAGE_SIZE);
+    println!("Boot table -> kernel table:");
+    page::print_mapping_diff(unsafe { &*(boot_root as *const page::Table) }, root);
+    // Nothing we map may be both writable and executable.
+    page::audit_wx(root);
 
     // The following shows how we're going to walk to translate a virtual
     // address into a physical address. We will use this whenever a user
     // space application requires services. Since the user space application
     // only knows virtual addresses, we have to translate silently behind
     // the scenes.
-    let p = 0x8005_7000 as usize;
-    let m = page::virt_to_phys(&root, p).unwrap_or(0);
+    let p = page::phys_to_virt(0x8005_7000);
+    let m = page::translate(&root, p).unwrap_or(0);
     println!("Walk 0x{:x} = 0x{:x}", p, m);
 
-    // When we return from here, we'll go back to boot.S and switch into
-    // supervisor mode We will return the SATP register to be written when
-    // we return. root_u is the root page table's address. When stored into
-    // the SATP register, this is divided by 4 KiB (right shift by 12 bits).
-    // We enable the MMU by setting mode 8. Bits 63, 62, 61, 60 determine
-    // the mode.
-    // 0 = Bare (no translation)
-    // 8 = Sv39
-    // 9 = Sv48
+    // When we return from here, we'll go back to boot.S, which turns on
+    // interrupts and calls __start_rust.
     unsafe {
         KERNEL_TABLE = root_u;
     }
 
-    // When we return from here, we'll go back to boot.S and switch into
-    // supervisor mode We will return the SATP register to be written when
-    // we return. root_u is the root page table's address. When stored into
-    // the SATP register, this is divided by 4 KiB (right shift by 12 bits).
-    // We enable the MMU by setting mode 8. Bits 63, 62, 61, 60 determine
-    // the mode.
+    // root_u is the kernel address of the root table, satp wants the
+    // physical one. When stored into the SATP register, this is divided by
+    // 4 KiB (right shift by 12 bits). Bits 63, 62, 61, 60 determine the
+    // mode.
     // 0 = Bare (no translation)
     // 8 = Sv39
     // 9 = Sv48
     // build_satp has these parameters: mode, asid, page table address.
-    let satp_value = cpu::build_satp(cpu::SatpMode::Sv39, 0, root_u);
+    // The kernel runs under ASID 0, which asid.rs keeps for it.
+    let satp_value = cpu::build_satp(page::paging_mode(), 0, page::virt_to_phys(root_u));
     unsafe {
         // We have to store the kernel's table. The tables will be moved
         // back and forth between the kernel's table and user
-        // applicatons' tables. Note that we're writing the physical address
-        // of the trap frame.
-        cpu::mscratch_write(&mut cpu::KERNEL_TRAP_FRAME[0] as *mut cpu::TrapFrame as usize);
-        cpu::sscratch_write(cpu::mscratch_read());
+        // applicatons' tables. s_trap_vector finds the trap frame in
+        // sscratch, which we set at the start.
         cpu::KERNEL_TRAP_FRAME[0].satp = satp_value;
+        cpu::KERNEL_TRAP_FRAME[0].hart_id = 0;
 
         // Move the stack pointer to the very bottom. The stack is
         // actually in a non-mapped page. The stack is decrement-before
         // push and increment after pop. Therefore, the stack will be
-        // allocated (decremented) before it is stored.
+        // allocated (decremented) before it is stored. Like the trap
+        // frame, it is in the direct map.
         cpu::KERNEL_TRAP_FRAME[0].trap_stack = page::zalloc(1).add(page::PAGE_SIZE);
-        id_map_range(
-            &mut root,
-            cpu::KERNEL_TRAP_FRAME[0].trap_stack.sub(page::PAGE_SIZE) as usize,
-            cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize,
-            page::EntryBits::ReadWrite.val(),
-        );
-        // The trap frame itself is stored in the mscratch register
-        id_map_range(
-            &mut root,
-            cpu::mscratch_read(),
-            cpu::mscratch_read() + core::mem::size_of::<cpu::TrapFrame>(),
-            page::EntryBits::ReadWrite.val(),
-        );
         page::print_page_allocations();
         let p = cpu::KERNEL_TRAP_FRAME[0].trap_stack as usize - 1;
-        let m = page::virt_to_phys(&root, p).unwrap_or(0);
+        let m = page::translate(&root, p).unwrap_or(0);
         println!("Walk 0x{:x} = 0x{:x}", p, m);
     }
-    // The following shows how we're going to walk to translate a virtual
-    // address into a physical address. We will use this whenever a user
-    // space application requires services. Since the user space application
-    // only knows virtual addresses, we have to translate silently behind
-    // the scenes.
-    println!("Setting 0x{:x}", satp_value);
-    println!("Scratch reg = 0x{:x}", cpu::mscratch_read());
+    // We are running at our link address in the upper half, which the new
+    // table maps as well, so we can simply switch over.
+    println!("Setting 0x{:x} ({:?})", satp_value, page::paging_mode());
+    println!("Scratch reg = 0x{:x}", cpu::sscratch_read());
     cpu::satp_write(satp_value);
     cpu::satp_fence_asid(0);
+    asid::init();
+    println!("ASID bits: {}", asid::asid_bits());
 
     println!("kinit....     [done]");
 }
 
 #[no_mangle]
 extern "C" fn __start_rust() {
-    let mut uart = Uart::new(0x1000_0000);
+    let mut uart = Uart::new(uart::base_address());
 
     {
         // We have the global allocator, so let's see if that works!
@@ -320,8 +306,8 @@ extern "C" fn __start_rust() {
 
     println!("Setting up interrupts and PLIC...");
     plic::set_threshold(0);
-    plic::enable(10);
-    plic::set_priority(10, 1);
+    plic::enable(uart::irq());
+    plic::set_priority(uart::irq(), 1);
     println!("UART interrupts have been enabled and are awaiting your command");
 }
 
@@ -332,14 +318,11 @@ extern "C" fn kinit_hart(hartid: usize) {
         // We have to store the kernel's table. The tables will be moved
         // back and forth between the kernel's table and user
         // applicatons' tables.
-        cpu::mscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize);
-        // Copy the same mscratch over to the supervisor version of the
-        // same register.
-        cpu::sscratch_write(cpu::mscratch_read());
+        cpu::sscratch_write((&mut cpu::KERNEL_TRAP_FRAME[hartid] as *mut cpu::TrapFrame) as usize);
         cpu::KERNEL_TRAP_FRAME[hartid].hart_id = hartid;
-        // We can't do the following until zalloc() is locked, but we
-        // don't have locks, yet :( cpu::KERNEL_TRAP_FRAME[hartid].satp
-        // = cpu::KERNEL_TRAP_FRAME[0].satp;
-        // cpu::KERNEL_TRAP_FRAME[hartid].trap_stack = page::zalloc(1);
+        // Not done yet: boot.S still parks this hart instead of calling
+        // us. Before we can take satp and a trap stack from zalloc() here,
+        // boot.S needs a barrier that hart 0 releases after page::init()
+        // and kmem::init().
     }
 }
@@ -0,0 +1,87 @@
+// What to do when memory runs out.
+// An allocation that fails is retried after each of these steps, from the
+// cheapest to the most drastic:
+//
+//  1. The allocators give back what they hold on to: empty slabs, and the
+//     objects and pages in this hart's caches. The other harts are asked
+//     to empty theirs, which they do the next time they allocate.
+//  2. User pages are reclaimed through the clock. Clean ones are dropped,
+//     dirty ones go out to swap.
+//  3. The address space with the most memory is killed (see
+//     vm::oom_kill()).
+//
+// Killing is only for allocations that must not fail, such as those of
+// the global allocator. Code that can cope with a failed allocation uses
+// the fallible ones in kmem, which stop after step 2.
+//
+// Reclaiming must not depend on the memory it is looking for, so an
+// allocation that fails while its hart is already reclaiming fails right
+// away instead of starting over.
+
+use crate::{cpu, kmem, page, swap, vm};
+use core::ptr::null_mut;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Reclaim {
+    /// Shrink the caches and reclaim user pages, but kill nothing.
+    NoKill,
+    /// Everything, up to killing an address space.
+    Kill,
+}
+
+// User pages to reclaim at least, per round.
+const RECLAIM_BATCH: usize = 32;
+
+// Which harts are in retry() right now. A hart only touches its own entry.
+static mut RECLAIMING: [bool; cpu::KERNEL_TRAP_FRAME_COUNT] = [false; cpu::KERNEL_TRAP_FRAME_COUNT];
+
+/// Call alloc until it returns something that is not null, freeing memory
+/// in between as described above. pages is about how much the allocation
+/// needs. Returns null if nothing helped, or if this hart is already
+/// reclaiming further up the stack.
+pub fn retry<F: FnMut() -> *mut u8>(mode: Reclaim, pages: usize, mut alloc: F) -> *mut u8 {
+    let ptr = alloc();
+    if !ptr.is_null() {
+        return ptr;
+    }
+    let hart = cpu::hart_id();
+    unsafe {
+        if RECLAIMING[hart] {
+            return null_mut();
+        }
+        RECLAIMING[hart] = true;
+    }
+    let ptr = reclaim(mode, pages, alloc);
+    unsafe {
+        RECLAIMING[hart] = false;
+    }
+    ptr
+}
+
+fn reclaim<F: FnMut() -> *mut u8>(mode: Reclaim, pages: usize, mut alloc: F) -> *mut u8 {
+    if kmem::shrink() + page::drain_hart_caches() > 0 {
+        let ptr = alloc();
+        if !ptr.is_null() {
+            return ptr;
+        }
+    }
+    // What we reclaim is single pages all over the place, so this may take
+    // a few rounds for an allocation that needs them contiguous.
+    while swap::reclaim(pages.max(RECLAIM_BATCH)) > 0 {
+        page::drain_hart_caches();
+        let ptr = alloc();
+        if !ptr.is_null() {
+            return ptr;
+        }
+    }
+    if mode == Reclaim::Kill {
+        while vm::oom_kill() > 0 {
+            page::drain_hart_caches();
+            let ptr = alloc();
+            if !ptr.is_null() {
+                return ptr;
+            }
+        }
+    }
+    null_mut()
+}
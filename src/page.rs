@@ -1,5 +1,8 @@
+use crate::cpu::{self, SatpMode};
+use crate::lock::IrqLock;
 use crate::{print, println};
-use core::{mem::size_of, ptr::null_mut};
+use core::sync::atomic::{AtomicU16, AtomicU8, AtomicUsize, Ordering};
+use core::{alloc::AllocError, cmp, fmt::Write, marker::PhantomData, mem::size_of, ptr::null_mut};
 
 extern "C" {
     static HEAP_START: usize;
@@ -7,11 +10,75 @@ extern "C" {
 }
 
 static mut ALLOC_START: usize = 0;
-const PAGE_ORDER: usize = 12;
-pub const PAGE_SIZE: usize = 1 << 12;
+// Number of pages that can actually be handed out. This is smaller than
+// the heap size / PAGE_SIZE since the descriptors themselves live at the
+// beginning of the heap.
+static mut ALLOC_PAGES: usize = 0;
+pub const PAGE_ORDER: usize = 12;
+pub const PAGE_SIZE: usize = 1 << PAGE_ORDER;
 
 const TABLE_SIZE: usize = 512;
 
+/// The kernel sees all of physical memory at a fixed offset in the upper
+/// half of the address space: physical address p is at PHYS_OFFSET + p.
+/// The kernel image is linked inside this window too, so kernel addresses
+/// and direct-mapped addresses follow the same rule. The offset is the
+/// start of the Sv39 upper half, which is also in the upper half of Sv48.
+pub const PHYS_OFFSET: usize = 0xffff_ffc0_0000_0000;
+
+/// Root table entries from here on map the upper half of the address
+/// space, which belongs to the kernel. Every address space shares them, so
+/// user space gets the entire lower half.
+pub const KERNEL_HALF: usize = TABLE_SIZE / 2;
+
+/// The top of the kernel half is kept out of the direct map: vmalloc.rs
+/// maps pages there. This leaves the direct map room for 192 GiB of
+/// physical addresses.
+pub const VMALLOC_START: usize = 0xffff_fff0_0000_0000;
+pub const VMALLOC_END: usize = VMALLOC_START + (4 << 30);
+
+// The translation scheme every table built by this module is laid out for.
+// Sv39 walks three levels, Sv48 four. It has to be chosen before the first
+// mapping is made and cannot change afterwards.
+static mut PAGING_MODE: SatpMode = SatpMode::Sv39;
+
+/// The largest block the buddy allocator manages is 2^MAX_ORDER pages
+/// (4 MiB). Bigger requests than that cannot be satisfied.
+pub const MAX_ORDER: usize = 10;
+
+// Marks the end of a free list and the absence of a neighbour.
+const NO_PAGE: u32 = u32::MAX;
+
+/// Physical memory is split into zones that are allocated from separately.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Zone {
+    /// The lowest DMA_ZONE_SIZE bytes of RAM, kept for alloc_aligned(),
+    /// so devices can get big contiguous buffers even when the rest of
+    /// memory is fragmented.
+    Dma = 0,
+    /// Everything else, which is what alloc() hands out.
+    Normal = 1,
+}
+
+const ZONES: usize = 2;
+
+/// Size of the DMA zone. It is rounded up to whole 2^MAX_ORDER page blocks,
+/// so no buddy block ever spans both zones, and takes at most a quarter of
+/// RAM.
+pub const DMA_ZONE_SIZE: usize = 16 << 20;
+
+// Pages with a descriptor index below this are in the DMA zone.
+static mut DMA_PAGES: usize = 0;
+
+// Each zone has a doubly-linked list of free blocks per order. The lists
+// hold descriptor indices and are threaded through the Page descriptors, so
+// we never have to touch the free memory itself (it might not be mapped).
+static mut FREE_LISTS: [[u32; MAX_ORDER + 1]; ZONES] = [[NO_PAGE; MAX_ORDER + 1]; ZONES];
+
+// Guards the free lists, the clock list and the page descriptors. Only the
+// per-hart caches are outside of it, see below.
+static PAGE_LOCK: IrqLock<()> = IrqLock::new(());
+
 pub const fn align_val(val: usize, order: usize) -> usize {
     let o = (1usize << order) - 1;
     (val + o) & !o
@@ -23,6 +90,11 @@ pub enum PageBits {
     Taken = 1 << 0,
     Last = 1 << 1,
     User = 1 << 2,
+    // First page of a free block that sits on one of the free lists.
+    Head = 1 << 3,
+    // An anonymous user page with exactly one PTE pointing at it, which
+    // sits on the clock list and can be swapped out.
+    Swappable = 1 << 4,
 }
 
 impl PageBits {
@@ -31,13 +103,31 @@ impl PageBits {
     }
 }
 
+// flags and refs are atomic because a hart changes them for the pages in
+// its own cache without taking PAGE_LOCK, while another hart may look at
+// them to merge a buddy. Everything else only changes under the lock.
 pub struct Page {
-    flags: u8,
+    flags: AtomicU8,
+    // Only meaningful for a Head page: the block spans 2^order pages.
+    order: u8,
+    // Number of owners of the allocation that starts at this page. Only
+    // the first page of an allocation carries a count.
+    refs: AtomicU16,
+    // Free blocks are linked into the free lists through prev and next,
+    // swappable pages into the clock list.
+    prev: u32,
+    next: u32,
+    // The entry that maps a swappable page.
+    pte: *mut Pte,
 }
 
 impl Page {
+    fn flags(&self) -> u8 {
+        self.flags.load(Ordering::Relaxed)
+    }
+
     pub fn is_last(&self) -> bool {
-        if self.flags & PageBits::Last.val() != 0 {
+        if self.flags() & PageBits::Last.val() != 0 {
             true
         } else {
             false
@@ -45,7 +135,7 @@ impl Page {
     }
 
     pub fn is_taken(&self) -> bool {
-        if self.flags & PageBits::Taken.val() != 0 {
+        if self.flags() & PageBits::Taken.val() != 0 {
             true
         } else {
             false
@@ -56,22 +146,184 @@ impl Page {
         !self.is_taken()
     }
 
+    pub fn is_head(&self) -> bool {
+        self.flags() & PageBits::Head.val() != 0
+    }
+
+    pub fn is_swappable(&self) -> bool {
+        self.flags() & PageBits::Swappable.val() != 0
+    }
+
+    fn refs(&self) -> u16 {
+        self.refs.load(Ordering::Relaxed)
+    }
+
+    fn set_refs(&self, refs: u16) {
+        self.refs.store(refs, Ordering::Relaxed);
+    }
+
     pub fn clear(&mut self) {
-        self.flags = PageBits::Empty.val();
+        self.flags.store(PageBits::Empty.val(), Ordering::Relaxed);
+        self.order = 0;
+        self.set_refs(0);
+        self.prev = NO_PAGE;
+        self.next = NO_PAGE;
+        self.pte = null_mut();
+    }
+
+    pub fn set_flag(&self, flag: PageBits) {
+        self.flags.fetch_or(flag.val(), Ordering::Relaxed);
+    }
+
+    pub fn clear_flag(&self, flag: PageBits) {
+        self.flags.fetch_and(!(flag.val()), Ordering::Relaxed);
+    }
+}
+
+// ///////////////////////////////////
+// / BUDDY ALLOCATOR
+// ///////////////////////////////////
+// Free memory is kept as blocks of 2^order pages. A block of order k is
+// always aligned to 2^k pages *physically*, so its buddy (the other half
+// of the order k + 1 block it came from) is found by flipping bit k of its
+// page frame number. Allocation splits a bigger block down to the order we
+// need, and freeing merges a block with its buddy for as long as the buddy
+// is free too. Both walk at most MAX_ORDER levels.
+
+unsafe fn descriptor(idx: usize) -> *mut Page {
+    (HEAP_START as *mut Page).add(idx)
+}
+
+// Page frame number of the descriptor index. Buddies are computed from the
+// physical address so that block alignment holds in physical memory.
+unsafe fn idx_to_pfn(idx: usize) -> usize {
+    (ALLOC_START >> PAGE_ORDER) + idx
+}
+
+unsafe fn buddy_of(idx: usize, order: usize) -> Option<usize> {
+    let base = ALLOC_START >> PAGE_ORDER;
+    let buddy = idx_to_pfn(idx) ^ (1 << order);
+    if buddy < base || buddy - base + (1 << order) > ALLOC_PAGES {
+        None
+    } else {
+        Some(buddy - base)
+    }
+}
+
+// Smallest order whose block covers the given number of pages.
+fn order_for(pages: usize) -> usize {
+    let mut order = 0;
+    while (1 << order) < pages {
+        order += 1;
+    }
+    order
+}
+
+unsafe fn zone_of(idx: usize) -> Zone {
+    if idx < DMA_PAGES {
+        Zone::Dma
+    } else {
+        Zone::Normal
     }
+}
 
-    pub fn set_flag(&mut self, flag: PageBits) {
-        self.flags |= flag.val();
+// A block always goes on the list of the zone it is in.
+unsafe fn list_push(order: usize, idx: usize) {
+    let lists = &mut FREE_LISTS[zone_of(idx) as usize];
+    let page = descriptor(idx);
+    let head = lists[order];
+    (*page).flags.store(PageBits::Head.val(), Ordering::Relaxed);
+    (*page).order = order as u8;
+    (*page).prev = NO_PAGE;
+    (*page).next = head;
+    if head != NO_PAGE {
+        (*descriptor(head as usize)).prev = idx as u32;
+    }
+    lists[order] = idx as u32;
+}
+
+unsafe fn list_remove(order: usize, idx: usize) {
+    let page = descriptor(idx);
+    let (prev, next) = ((*page).prev, (*page).next);
+    if prev == NO_PAGE {
+        FREE_LISTS[zone_of(idx) as usize][order] = next;
+    } else {
+        (*descriptor(prev as usize)).next = next;
+    }
+    if next != NO_PAGE {
+        (*descriptor(next as usize)).prev = prev;
+    }
+    (*page).clear();
+}
+
+// Put a single block back and merge it with its buddies as far as possible.
+unsafe fn free_block(mut idx: usize, mut order: usize) {
+    while order < MAX_ORDER {
+        match buddy_of(idx, order) {
+            Some(buddy)
+                if (*descriptor(buddy)).is_head()
+                    && (*descriptor(buddy)).order as usize == order =>
+            {
+                list_remove(order, buddy);
+                if buddy < idx {
+                    idx = buddy;
+                }
+                order += 1;
+            }
+            _ => break,
+        }
     }
+    list_push(order, idx);
+}
 
-    pub fn clear_flag(&mut self, flag: PageBits) {
-        self.flags &= !(flag.val());
+// Free an arbitrary run of pages by cutting it into the largest aligned
+// blocks that fit.
+unsafe fn free_range(mut idx: usize, mut count: usize) {
+    while count > 0 {
+        let mut order = 0;
+        while order < MAX_ORDER
+            && idx_to_pfn(idx) & ((1 << (order + 1)) - 1) == 0
+            && (1 << (order + 1)) <= count
+        {
+            order += 1;
+        }
+        free_block(idx, order);
+        idx += 1 << order;
+        count -= 1 << order;
     }
 }
 
-pub fn init() {
+/// Where physical address paddr is in the kernel's direct map.
+pub const fn phys_to_virt(paddr: usize) -> usize {
+    paddr + PHYS_OFFSET
+}
+
+/// The physical address behind a kernel address, which is either in the
+/// kernel image or in the direct map. vmalloc addresses have no single
+/// physical address and have to go through translate().
+pub fn virt_to_phys(vaddr: usize) -> usize {
+    assert!(
+        (PHYS_OFFSET..VMALLOC_START).contains(&vaddr),
+        "0x{:x} is not in the direct map",
+        vaddr
+    );
+    vaddr - PHYS_OFFSET
+}
+
+/// Hand all RAM from the end of the kernel image up to the physical
+/// address memory_end to the allocator. The linker script only knows the
+/// smallest RAM we run in, so the real end comes from the device tree.
+/// Pass None to use the linker's idea of it.
+pub fn init(memory_end: Option<usize>) {
     unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        // Whatever does not fit under the vmalloc region cannot be used.
+        let heap_end = memory_end
+            .map(phys_to_virt)
+            .unwrap_or(HEAP_START + HEAP_SIZE)
+            .min(VMALLOC_START)
+            & !(PAGE_SIZE - 1);
+        assert!(heap_end > HEAP_START, "No RAM left after the kernel image");
+        let num_pages = (heap_end - HEAP_START) / PAGE_SIZE;
         let ptr = HEAP_START as *mut Page;
 
         for i in 0..num_pages {
@@ -79,41 +331,332 @@ pub fn init() {
         }
 
         ALLOC_START = align_val(HEAP_START + num_pages * size_of::<Page>(), PAGE_ORDER);
+        ALLOC_PAGES = (heap_end - ALLOC_START) / PAGE_SIZE;
+        FREE_LISTS = [[NO_PAGE; MAX_ORDER + 1]; ZONES];
+        // The zone boundary sits on a 2^MAX_ORDER page boundary in physical
+        // memory, so blocks are never merged across it.
+        let dma_pages = (DMA_ZONE_SIZE / PAGE_SIZE).min(ALLOC_PAGES / 4);
+        let boundary = align_val(idx_to_pfn(dma_pages), MAX_ORDER);
+        DMA_PAGES = (boundary - idx_to_pfn(0)).min(ALLOC_PAGES);
+        free_range(0, ALLOC_PAGES);
     }
 }
 
-pub fn alloc(pages: usize) -> *mut u8 {
-    assert!(pages > 0);
+/// Physical end of the memory the allocator manages, which is the end of
+/// RAM.
+pub fn memory_end() -> usize {
+    unsafe { virt_to_phys(ALLOC_START + ALLOC_PAGES * PAGE_SIZE) }
+}
+
+// The free block that idx is part of, as (first page, order).
+unsafe fn containing_free_block(idx: usize) -> Option<(usize, usize)> {
+    let base = ALLOC_START >> PAGE_ORDER;
+    for order in 0..=MAX_ORDER {
+        let head = idx_to_pfn(idx) & !((1 << order) - 1);
+        if head < base {
+            break;
+        }
+        let page = descriptor(head - base);
+        if (*page).is_head() && (*page).order as usize == order {
+            return Some((head - base, order));
+        }
+    }
+    None
+}
+
+// Whether every page in [first, last) is free.
+unsafe fn range_free(first: usize, last: usize) -> bool {
+    let mut idx = first;
+    while idx < last {
+        match containing_free_block(idx) {
+            Some((head, order)) => idx = head + (1 << order),
+            None => return false,
+        }
+    }
+    true
+}
+
+// Take the pages [first, last), which have to be free, off the free lists.
+unsafe fn take_range(first: usize, last: usize) {
+    let mut idx = first;
+    while idx < last {
+        let (head, order) = containing_free_block(idx).unwrap();
+        list_remove(order, head);
+        // Whatever the block has outside [idx, stop) goes back.
+        let block_end = head + (1 << order);
+        let stop = block_end.min(last);
+        if head < idx {
+            free_range(head, idx - head);
+        }
+        if block_end > stop {
+            free_range(stop, block_end - stop);
+        }
+        idx = stop;
+    }
+}
+
+/// Take the physical range [start, end) out of the free memory before
+/// anybody else can get it, e.g. for the device tree blob. The range shows
+/// up as one allocation, so it can be given back with
+/// dealloc(phys_to_virt(start)) later. Parts outside of the allocator's
+/// memory are ignored.
+pub fn reserve(start: usize, end: usize) {
     unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
-        let ptr = HEAP_START as *mut Page;
+        let alloc_end = ALLOC_START + ALLOC_PAGES * PAGE_SIZE;
+        let start = (phys_to_virt(start) & !(PAGE_SIZE - 1)).max(ALLOC_START);
+        let end = align_val(phys_to_virt(end), PAGE_ORDER).min(alloc_end);
+        if start >= end {
+            return;
+        }
+        let first = (start - ALLOC_START) / PAGE_SIZE;
+        let last = (end - ALLOC_START) / PAGE_SIZE;
+        let _lock = PAGE_LOCK.lock();
+        if !range_free(first, last) {
+            panic!("Cannot reserve 0x{:x}, it is in use", start);
+        }
+        take_range(first, last);
 
-        for i in 0..=num_pages - pages {
-            let mut found = false;
+        for i in first..last {
+            (*descriptor(i)).set_flag(PageBits::Taken);
+        }
+        (*descriptor(last - 1)).set_flag(PageBits::Last);
+        (*descriptor(first)).set_refs(1);
+    }
+}
 
-            if (*ptr.add(i)).is_free() {
-                found = true;
-                for j in i..i + pages {
-                    if (*ptr.add(j)).is_taken() {
-                        found = false;
-                        break;
-                    }
+// Take `pages` pages out of a block of the given order in the zone, and
+// return the index of the first one. Since blocks are aligned to their
+// size, the run is aligned to 2^order pages.
+unsafe fn alloc_block(zone: Zone, pages: usize, order: usize) -> Option<usize> {
+    let lists = &FREE_LISTS[zone as usize];
+    let mut k = order;
+    while k <= MAX_ORDER && lists[k] == NO_PAGE {
+        k += 1;
+    }
+    if k > MAX_ORDER {
+        return None;
+    }
+
+    let idx = lists[k] as usize;
+    list_remove(k, idx);
+    // Split the block in half until it is the order we asked for. The
+    // upper half always goes back onto the free list.
+    while k > order {
+        k -= 1;
+        list_push(k, idx + (1 << k));
+    }
+
+    for i in idx..idx + pages {
+        (*descriptor(i)).set_flag(PageBits::Taken);
+    }
+    (*descriptor(idx + pages - 1)).set_flag(PageBits::Last);
+    (*descriptor(idx)).set_refs(1);
+
+    if (1 << order) > pages {
+        free_range(idx + pages, (1 << order) - pages);
+    }
+    Some(idx)
+}
+
+// ///////////////////////////////////
+// / PER-HART PAGE CACHES
+// ///////////////////////////////////
+// Every hart keeps a few free pages of the Normal zone to itself, so that
+// alloc(1) and freeing a single page mostly stay away from the free lists
+// all harts share. A hart only ever touches its own cache, with its
+// interrupts off, and needs no lock for that. An empty cache takes
+// HART_BATCH pages from the free lists in one go, and a full one gives
+// HART_BATCH back, so PAGE_LOCK is only taken once per batch. When memory
+// runs out, drain_hart_caches() empties this hart's cache and leaves a
+// request for the other harts to empty theirs.
+//
+// A page in a cache is neither allocated nor on a free list. Its
+// descriptor is empty, so no buddy merges with it, and resize() and
+// reserve() see it as in use.
+
+const HART_CACHE_SIZE: usize = 32;
+const HART_BATCH: usize = 16;
+
+#[derive(Clone, Copy)]
+struct HartCache {
+    count: usize,
+    pages: [u32; HART_CACHE_SIZE],
+}
+
+const EMPTY_HART_CACHE: HartCache = HartCache {
+    count: 0,
+    pages: [NO_PAGE; HART_CACHE_SIZE],
+};
+
+static mut HART_CACHES: [HartCache; cpu::KERNEL_TRAP_FRAME_COUNT] =
+    [EMPTY_HART_CACHE; cpu::KERNEL_TRAP_FRAME_COUNT];
+
+// Harts that drain_hart_caches() asked to empty their cache, one bit each.
+static DRAIN_REQUESTS: AtomicUsize = AtomicUsize::new(0);
+
+// This hart's cache, emptied first if drain_hart_caches() asked for it.
+// Interrupts have to be off.
+unsafe fn hart_cache() -> &'static mut HartCache {
+    let hart = cpu::hart_id();
+    let cache = &mut HART_CACHES[hart];
+    if DRAIN_REQUESTS.load(Ordering::Relaxed) & 1 << hart != 0 {
+        DRAIN_REQUESTS.fetch_and(!(1 << hart), Ordering::Relaxed);
+        let count = cache.count;
+        hart_flush(cache, count);
+    }
+    cache
+}
+
+// Take a page out of this hart's cache, refilling it first if it is empty.
+// Interrupts have to be off.
+unsafe fn hart_alloc() -> Option<usize> {
+    let cache = hart_cache();
+    if cache.count == 0 {
+        let _lock = PAGE_LOCK.lock();
+        while cache.count < HART_BATCH {
+            match alloc_block(Zone::Normal, 1, 0) {
+                Some(idx) => {
+                    (*descriptor(idx)).clear();
+                    cache.pages[cache.count] = idx as u32;
+                    cache.count += 1;
                 }
+                None => break,
             }
+        }
+    }
+    if cache.count == 0 {
+        return None;
+    }
+    cache.count -= 1;
+    let idx = cache.pages[cache.count] as usize;
+    let page = descriptor(idx);
+    (*page).set_flag(PageBits::Taken);
+    (*page).set_flag(PageBits::Last);
+    (*page).set_refs(1);
+    Some(idx)
+}
 
-            if found {
-                for k in i..i + pages - 1 {
-                    (*ptr.add(k)).set_flag(PageBits::Taken);
-                }
+// Put a page that was just freed into this hart's cache. Interrupts have to
+// be off.
+unsafe fn hart_free(idx: usize) {
+    let cache = hart_cache();
+    if cache.count == HART_CACHE_SIZE {
+        hart_flush(cache, HART_BATCH);
+    }
+    cache.pages[cache.count] = idx as u32;
+    cache.count += 1;
+}
 
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Taken);
-                (*ptr.add(i + pages - 1)).set_flag(PageBits::Last);
+// Give the count pages that have been in the cache longest back to the
+// free lists.
+unsafe fn hart_flush(cache: &mut HartCache, count: usize) {
+    let count = count.min(cache.count);
+    let _lock = PAGE_LOCK.lock();
+    for i in 0..count {
+        free_block(cache.pages[i] as usize, 0);
+    }
+    cache.pages.copy_within(count..cache.count, 0);
+    cache.count -= count;
+}
 
-                return (ALLOC_START + PAGE_SIZE * i) as *mut u8;
-            }
+/// Give the pages this hart keeps for itself back to the free lists, where
+/// they can merge into bigger blocks again, and ask the other harts to do
+/// the same the next time they allocate or free a single page. Returns how
+/// many pages this hart gave back.
+pub fn drain_hart_caches() -> usize {
+    let hart = cpu::hart_id();
+    let all = (1 << cpu::KERNEL_TRAP_FRAME_COUNT) - 1;
+    DRAIN_REQUESTS.fetch_or(all & !(1 << hart), Ordering::Relaxed);
+    let on = cpu::interrupts_disable();
+    let count = unsafe {
+        let cache = &mut HART_CACHES[hart];
+        let count = cache.count;
+        hart_flush(cache, count);
+        count
+    };
+    cpu::interrupts_restore(on);
+    count
+}
+
+/// Allocate a run of contiguous pages. The run comes out of the smallest
+/// buddy block that fits, and whatever is left at the tail of that block
+/// goes straight back to the free lists.
+pub fn alloc(pages: usize) -> *mut u8 {
+    alloc_align(pages, PAGE_SIZE)
+}
+
+/// Like alloc(), but the run starts at a multiple of `align` bytes, which
+/// has to be a power of two. Every run is page aligned anyway; a bigger
+/// alignment takes a block of at least that size, so it gives back null
+/// sooner once memory is fragmented.
+pub fn alloc_align(pages: usize, align: usize) -> *mut u8 {
+    assert!(pages > 0);
+    assert!(
+        align.is_power_of_two(),
+        "Alignment {} is not a power of two",
+        align
+    );
+    if pages == 1 && align <= PAGE_SIZE {
+        let on = cpu::interrupts_disable();
+        let idx = unsafe { hart_alloc() };
+        cpu::interrupts_restore(on);
+        return match idx {
+            Some(idx) => unsafe { (ALLOC_START + PAGE_SIZE * idx) as *mut u8 },
+            None => null_mut(),
+        };
+    }
+    let order = order_for(pages).max(order_for(align / PAGE_SIZE));
+    if order > MAX_ORDER {
+        return null_mut();
+    }
+    let _lock = PAGE_LOCK.lock();
+    unsafe {
+        match alloc_block(Zone::Normal, pages, order) {
+            Some(idx) => (ALLOC_START + PAGE_SIZE * idx) as *mut u8,
+            None => null_mut(),
         }
     }
-    null_mut()
+}
+
+/// A physically contiguous buffer, as a device needs it.
+#[derive(Clone, Copy, Debug)]
+pub struct DmaBuffer {
+    /// The address to hand to the device.
+    pub paddr: usize,
+    /// Where the kernel reaches the same memory.
+    pub vaddr: *mut u8,
+    pub pages: usize,
+}
+
+/// Allocate `pages` zeroed pages from the DMA zone, starting at a physical
+/// address that is a multiple of `align` bytes. align has to be a power of
+/// two, and anything up to the largest block (2^MAX_ORDER pages) is
+/// possible; smaller than a page just means page aligned. Give the buffer
+/// back with dealloc(buffer.vaddr).
+pub fn alloc_aligned(pages: usize, align: usize) -> Option<DmaBuffer> {
+    assert!(pages > 0);
+    assert!(
+        align.is_power_of_two(),
+        "Alignment {} is not a power of two",
+        align
+    );
+    let order = order_for(pages).max(order_for(align / PAGE_SIZE));
+    if order > MAX_ORDER {
+        return None;
+    }
+    let idx = {
+        let _lock = PAGE_LOCK.lock();
+        unsafe { alloc_block(Zone::Dma, pages, order)? }
+    };
+    let vaddr = unsafe { (ALLOC_START + PAGE_SIZE * idx) as *mut u8 };
+    unsafe {
+        core::ptr::write_bytes(vaddr, 0, pages * PAGE_SIZE);
+    }
+    Some(DmaBuffer {
+        paddr: virt_to_phys(vaddr as usize),
+        vaddr,
+        pages,
+    })
 }
 
 pub fn zalloc(pages: usize) -> *mut u8 {
@@ -134,13 +677,45 @@ pub fn zalloc(pages: usize) -> *mut u8 {
 pub fn dealloc(ptr: *mut u8) {
     assert!(!ptr.is_null());
     unsafe {
-        let addr = HEAP_START + (ptr as usize - ALLOC_START) / PAGE_SIZE;
-
-        assert!(addr >= HEAP_START && addr < HEAP_START + HEAP_SIZE);
-        let mut p = addr as *mut Page;
+        let addr = ptr as usize;
+        assert!(addr >= ALLOC_START && addr < ALLOC_START + ALLOC_PAGES * PAGE_SIZE);
+        let idx = (addr - ALLOC_START) / PAGE_SIZE;
+        let mut p = descriptor(idx);
+        // A single page that nobody else knows about goes into this hart's
+        // cache without PAGE_LOCK. Anything else may be on the clock list
+        // or span several blocks, and goes through the free lists.
+        if (*p).flags() == PageBits::Taken.val() | PageBits::Last.val()
+            && (*p).refs() == 1
+            && zone_of(idx) == Zone::Normal
+        {
+            let on = cpu::interrupts_disable();
+            (*p).set_refs(0);
+            (*p).clear_flag(PageBits::Taken);
+            (*p).clear_flag(PageBits::Last);
+            hart_free(idx);
+            cpu::interrupts_restore(on);
+            return;
+        }
+        let _lock = PAGE_LOCK.lock();
+        let mut count = 0;
+        assert!(
+            (*p).is_free() || (*p).refs() > 0,
+            "dealloc of 0x{:x}, which is in the middle of an allocation",
+            addr
+        );
+        assert!(
+            (*p).refs() <= 1,
+            "dealloc of 0x{:x}, which is still shared ({} references), use put_page",
+            addr,
+            (*p).refs()
+        );
+        if (*p).is_swappable() {
+            clock_remove(idx);
+        }
         while (*p).is_taken() && !(*p).is_last() {
             (*p).clear();
             p = p.add(1);
+            count += 1;
         }
 
         assert!(
@@ -148,14 +723,294 @@ pub fn dealloc(ptr: *mut u8) {
             "Possible double-free detected! (Not taken found before last)"
         );
         (*p).clear();
+        free_range(idx, count + 1);
+    }
+}
+
+// Descriptor of the allocation that addr belongs to. The count lives on
+// the first page, so for a page in the middle of a multi-page allocation we
+// walk back to it.
+unsafe fn allocation(addr: usize) -> Option<*mut Page> {
+    if addr < ALLOC_START || addr >= ALLOC_START + ALLOC_PAGES * PAGE_SIZE {
+        return None;
+    }
+    let mut idx = (addr - ALLOC_START) / PAGE_SIZE;
+    loop {
+        let page = descriptor(idx);
+        if (*page).is_free() {
+            return None;
+        } else if (*page).refs() > 0 {
+            return Some(page);
+        } else if idx == 0 || (*descriptor(idx - 1)).is_last() {
+            // Taken without an owner in front of it. This should never
+            // happen.
+            return None;
+        }
+        idx -= 1;
+    }
+}
+
+/// Address of the first page of the allocation that addr points into, or
+/// None if the page allocator did not hand it out.
+pub fn allocation_start(addr: usize) -> Option<usize> {
+    let _lock = PAGE_LOCK.lock();
+    unsafe { allocation(addr).map(|page| ALLOC_START + page_index(page) * PAGE_SIZE) }
+}
+
+/// allocation_start() without PAGE_LOCK, for an address that the caller
+/// knows to be inside a live allocation, such as an object it is about to
+/// free. The descriptors of that allocation cannot change until it is
+/// freed, and the walk back to its first page does not look at any other.
+///
+/// # Safety
+/// addr has to be inside an allocation that nobody frees or resizes while
+/// this runs.
+pub unsafe fn live_allocation_start(addr: usize) -> Option<usize> {
+    allocation(addr).map(|page| ALLOC_START + page_index(page) * PAGE_SIZE)
+}
+
+/// Number of pages in the allocation that starts at addr.
+pub fn allocation_pages(addr: usize) -> usize {
+    let _lock = PAGE_LOCK.lock();
+    unsafe { pages_of(addr) }
+}
+
+// allocation_pages() for callers that hold PAGE_LOCK already.
+unsafe fn pages_of(addr: usize) -> usize {
+    let page = allocation(addr)
+        .filter(|&page| ALLOC_START + page_index(page) * PAGE_SIZE == addr)
+        .unwrap_or_else(|| panic!("0x{:x} is not the start of an allocation", addr));
+    let first = page_index(page);
+    let mut idx = first;
+    while !(*descriptor(idx)).is_last() {
+        idx += 1;
+    }
+    idx - first + 1
+}
+
+/// Make the allocation that starts at addr `pages` pages long without
+/// moving it. Shrinking always works and frees the tail. Growing only works
+/// if the pages right behind the allocation are free, and returns false,
+/// leaving the allocation as it was, if they are not.
+pub fn resize(addr: usize, pages: usize) -> bool {
+    assert!(pages > 0);
+    let _lock = PAGE_LOCK.lock();
+    unsafe {
+        let count = pages_of(addr);
+        let idx = (addr - ALLOC_START) / PAGE_SIZE;
+        assert!(
+            (*descriptor(idx)).refs() == 1,
+            "resize of 0x{:x}, which is shared",
+            addr
+        );
+        match pages.cmp(&count) {
+            cmp::Ordering::Less => {
+                for i in idx + pages..idx + count {
+                    (*descriptor(i)).clear();
+                }
+                (*descriptor(idx + pages - 1)).set_flag(PageBits::Last);
+                free_range(idx + pages, count - pages);
+            }
+            cmp::Ordering::Greater => {
+                let (first, last) = (idx + count, idx + pages);
+                // The run must not spill into the next zone, or past the
+                // end of memory.
+                if last > ALLOC_PAGES
+                    || zone_of(last - 1) != zone_of(idx)
+                    || !range_free(first, last)
+                {
+                    return false;
+                }
+                take_range(first, last);
+                (*descriptor(first - 1)).clear_flag(PageBits::Last);
+                for i in first..last {
+                    (*descriptor(i)).set_flag(PageBits::Taken);
+                }
+                (*descriptor(last - 1)).set_flag(PageBits::Last);
+            }
+            cmp::Ordering::Equal => {}
+        }
+    }
+    true
+}
+
+/// Number of owners of the allocation that addr points into. This is 0 for
+/// anything the page allocator did not hand out: MMIO, the kernel image
+/// and free pages.
+pub fn page_refs(addr: usize) -> usize {
+    let _lock = PAGE_LOCK.lock();
+    unsafe { allocation(addr).map_or(0, |page| (*page).refs() as usize) }
+}
+
+/// Take another reference to the allocation that addr points into, e.g.
+/// when the same page gets mapped into a second address space. The count
+/// covers the whole allocation, so sharing one page of a bigger run keeps
+/// all of it alive.
+pub fn get_page(addr: usize) {
+    let _lock = PAGE_LOCK.lock();
+    unsafe {
+        let page = allocation(addr).expect("get_page on a page that is not allocated");
+        assert!((*page).refs() < u16::MAX, "Page reference count overflow");
+        (*page).set_refs((*page).refs() + 1);
+        // More than one entry maps it now, and we only know one of them.
+        if (*page).is_swappable() {
+            clock_remove(page_index(page));
+        }
+    }
+}
+
+/// Drop a reference to the allocation that addr points into. The pages go
+/// back to the allocator when the last reference is gone. Returns true if
+/// that happened.
+pub fn put_page(addr: usize) -> bool {
+    let lock = PAGE_LOCK.lock();
+    unsafe {
+        let page = allocation(addr).expect("put_page on a page that is not allocated");
+        (*page).set_refs((*page).refs() - 1);
+        if (*page).refs() == 0 {
+            // dealloc() wants the owner to be the only one left, and takes
+            // the lock itself. Nobody else can get at the pages any more.
+            (*page).set_refs(1);
+            drop(lock);
+            dealloc((ALLOC_START + page_index(page) * PAGE_SIZE) as *mut u8);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+unsafe fn page_index(page: *mut Page) -> usize {
+    (page as usize - HEAP_START) / size_of::<Page>()
+}
+
+// ///////////////////////////////////
+// / PAGE REPLACEMENT
+// ///////////////////////////////////
+// Pages that may be swapped out form a circular list, and the clock hand
+// goes around it looking for a page that was not accessed since the last
+// time it came by. Pages that were get their accessed bit cleared, and
+// with it a second chance. New pages go in right behind the hand, so they
+// are the last it reaches.
+
+static mut CLOCK_HAND: u32 = NO_PAGE;
+static mut CLOCK_PAGES: usize = 0;
+
+unsafe fn clock_insert(idx: usize) {
+    let page = descriptor(idx);
+    if CLOCK_HAND == NO_PAGE {
+        (*page).prev = idx as u32;
+        (*page).next = idx as u32;
+        CLOCK_HAND = idx as u32;
+    } else {
+        let next = CLOCK_HAND;
+        let prev = (*descriptor(next as usize)).prev;
+        (*page).prev = prev;
+        (*page).next = next;
+        (*descriptor(prev as usize)).next = idx as u32;
+        (*descriptor(next as usize)).prev = idx as u32;
+    }
+    (*page).set_flag(PageBits::Swappable);
+    CLOCK_PAGES += 1;
+}
+
+unsafe fn clock_remove(idx: usize) {
+    let page = descriptor(idx);
+    let (prev, next) = ((*page).prev, (*page).next);
+    if next == idx as u32 {
+        CLOCK_HAND = NO_PAGE;
+    } else {
+        (*descriptor(prev as usize)).next = next;
+        (*descriptor(next as usize)).prev = prev;
+        if CLOCK_HAND == idx as u32 {
+            CLOCK_HAND = next;
+        }
+    }
+    (*page).prev = NO_PAGE;
+    (*page).next = NO_PAGE;
+    (*page).pte = null_mut();
+    (*page).clear_flag(PageBits::Swappable);
+    CLOCK_PAGES -= 1;
+}
+
+/// Let the page at addr be swapped out. pte has to be the only entry that
+/// maps it, and has to stay where it is for as long as the page is
+/// swappable. Freeing the page or sharing it with get_page() takes it off
+/// the list again.
+pub fn set_swappable(addr: usize, pte: *mut Pte) {
+    let _lock = PAGE_LOCK.lock();
+    unsafe {
+        let page = allocation(addr).expect("set_swappable on a page that is not allocated");
+        assert!(
+            (*page).refs() == 1,
+            "set_swappable on a shared page 0x{:x}",
+            addr
+        );
+        if !(*page).is_swappable() {
+            clock_insert(page_index(page));
+        }
+        (*page).pte = pte;
+    }
+}
+
+/// Keep the page at addr in memory from now on.
+pub fn clear_swappable(addr: usize) {
+    let _lock = PAGE_LOCK.lock();
+    unsafe {
+        if let Some(page) = allocation(addr) {
+            if (*page).is_swappable() {
+                clock_remove(page_index(page));
+            }
+        }
+    }
+}
+
+/// Number of pages that could be swapped out.
+pub fn swappable_pages() -> usize {
+    let _lock = PAGE_LOCK.lock();
+    unsafe { CLOCK_PAGES }
+}
+
+/// Run the clock until it finds a page that has not been accessed lately,
+/// and return its address and the entry that maps it. The page stays on
+/// the list until it is freed. Returns None if there is nothing to swap.
+pub fn clock_victim() -> Option<(usize, *mut Pte)> {
+    let _lock = PAGE_LOCK.lock();
+    unsafe {
+        let mut cleared = false;
+        let mut victim = None;
+        // The first round may only clear accessed bits, the second one is
+        // sure to find a page.
+        for _ in 0..2 * CLOCK_PAGES {
+            let idx = CLOCK_HAND as usize;
+            let page = descriptor(idx);
+            CLOCK_HAND = (*page).next;
+            let pte = &mut *(*page).pte;
+            if pte.is_accessed() {
+                *pte = pte
+                    .with_flags(pte.flags().difference(PteFlags::ACCESS))
+                    .unwrap();
+                cleared = true;
+            } else {
+                victim = Some((ALLOC_START + idx * PAGE_SIZE, (*page).pte));
+                break;
+            }
+        }
+        // The TLB may still hold the entries with the accessed bit set, and
+        // then nobody would set it again.
+        if cleared {
+            cpu::satp_fence_all();
+        }
+        victim
     }
 }
 
 /// Print all page allocations
 /// This is mainly used for debugging.
 pub fn print_page_allocations() {
+    let _lock = PAGE_LOCK.lock();
     unsafe {
-        let num_pages = HEAP_SIZE / PAGE_SIZE;
+        let num_pages = ALLOC_PAGES;
         let mut beg = HEAP_START as *const Page;
         let end = beg.add(num_pages);
         let alloc_beg = ALLOC_START;
@@ -164,21 +1019,28 @@ pub fn print_page_allocations() {
         println!(
             "PAGE ALLOCATION TABLE\nMETA: {:p} -> {:p}\nPHYS: \
 					0x{:x} -> 0x{:x}",
-            beg, end, alloc_beg, alloc_end
+            beg,
+            end,
+            virt_to_phys(alloc_beg),
+            virt_to_phys(alloc_end)
         );
         println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
         let mut num = 0;
         while beg < end {
             if (*beg).is_taken() {
-                let start = beg as usize;
-                let memaddr = ALLOC_START + (start - HEAP_START) * PAGE_SIZE;
+                let start = (beg as usize - HEAP_START) / size_of::<Page>();
+                let memaddr = ALLOC_START + start * PAGE_SIZE;
+                let refs = (*beg).refs();
                 print!("0x{:x} => ", memaddr);
                 loop {
                     num += 1;
                     if (*beg).is_last() {
-                        let end = beg as usize;
-                        let memaddr = ALLOC_START + (end - HEAP_START) * PAGE_SIZE + PAGE_SIZE - 1;
+                        let end = (beg as usize - HEAP_START) / size_of::<Page>();
+                        let memaddr = ALLOC_START + end * PAGE_SIZE + PAGE_SIZE - 1;
                         print!("0x{:x}: {:>3} page(s)", memaddr, (end - start + 1));
+                        if refs > 1 {
+                            print!(", shared by {}", refs);
+                        }
                         println!(".");
                         break;
                     }
@@ -198,72 +1060,303 @@ pub fn print_page_allocations() {
             num_pages - num,
             (num_pages - num) * PAGE_SIZE
         );
+        let cached: usize = HART_CACHES.iter().map(|cache| cache.count).sum();
+        println!("Of those in hart caches: {:>6} pages.", cached);
+        for &zone in [Zone::Dma, Zone::Normal].iter() {
+            print!("Free {:?} blocks by order:", zone);
+            for order in 0..=MAX_ORDER {
+                let mut blocks = 0;
+                let mut idx = FREE_LISTS[zone as usize][order];
+                while idx != NO_PAGE {
+                    blocks += 1;
+                    idx = (*descriptor(idx as usize)).next;
+                }
+                print!(" {}", blocks);
+            }
+            println!();
+        }
         println!();
     }
 }
 
-#[repr(i64)]
-#[derive(Copy, Clone)]
-pub enum EntryBits {
-    None = 0,
-    Valid = 1 << 0,
-    Read = 1 << 1,
-    Write = 1 << 2,
-    Execute = 1 << 3,
-    User = 1 << 4,
-    Global = 1 << 5,
-    Access = 1 << 6,
-    Dirty = 1 << 7,
+// ///////////////////////////////////
+// / PAGE TABLE ENTRIES
+// ///////////////////////////////////
+
+/// The ten low bits of a page table entry: V, R, W, X, U, G, A, D and the
+/// two bits reserved for the supervisor (RSW).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PteFlags(u64);
+
+impl PteFlags {
+    pub const NONE: PteFlags = PteFlags(0);
+    pub const VALID: PteFlags = PteFlags(1 << 0);
+    pub const READ: PteFlags = PteFlags(1 << 1);
+    pub const WRITE: PteFlags = PteFlags(1 << 2);
+    pub const EXECUTE: PteFlags = PteFlags(1 << 3);
+    pub const USER: PteFlags = PteFlags(1 << 4);
+    pub const GLOBAL: PteFlags = PteFlags(1 << 5);
+    pub const ACCESS: PteFlags = PteFlags(1 << 6);
+    pub const DIRTY: PteFlags = PteFlags(1 << 7);
+    // The hardware ignores the two RSW bits, so we use them for our own
+    // bookkeeping. COW marks a shared page that has to be copied before
+    // it may be written. SWAPPED marks an invalid entry whose page is out
+    // in swap.
+    pub const COW: PteFlags = PteFlags(1 << 8);
+    pub const SWAPPED: PteFlags = PteFlags(1 << 9);
 
     // Convenience combinations
-    ReadWrite = 1 << 1 | 1 << 2,
-    ReadExecute = 1 << 1 | 1 << 3,
-    ReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3,
+    pub const READ_WRITE: PteFlags = PteFlags(1 << 1 | 1 << 2);
+    pub const READ_EXECUTE: PteFlags = PteFlags(1 << 1 | 1 << 3);
+    pub const READ_WRITE_EXECUTE: PteFlags = PteFlags(1 << 1 | 1 << 2 | 1 << 3);
 
     // User Convenience Combinations
-    UserReadWrite = 1 << 1 | 1 << 2 | 1 << 4,
-    UserReadExecute = 1 << 1 | 1 << 3 | 1 << 4,
-    UserReadWriteExecute = 1 << 1 | 1 << 2 | 1 << 3 | 1 << 4,
+    pub const USER_READ_WRITE: PteFlags = PteFlags(1 << 1 | 1 << 2 | 1 << 4);
+    pub const USER_READ_EXECUTE: PteFlags = PteFlags(1 << 1 | 1 << 3 | 1 << 4);
+    pub const USER_READ_WRITE_EXECUTE: PteFlags = PteFlags(1 << 1 | 1 << 2 | 1 << 3 | 1 << 4);
+
+    const MASK: u64 = 0x3ff;
+    const RWX: u64 = 0xe;
+
+    /// Build flags from raw bits. Anything above bit 9 is not a flag.
+    pub const fn from_bits(bits: u64) -> Option<PteFlags> {
+        if bits & !Self::MASK != 0 {
+            None
+        } else {
+            Some(PteFlags(bits))
+        }
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn contains(self, other: PteFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn difference(self, other: PteFlags) -> PteFlags {
+        PteFlags(self.0 & !other.0)
+    }
+
+    /// Only the R, W and X bits.
+    pub const fn permissions(self) -> PteFlags {
+        PteFlags(self.0 & Self::RWX)
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.contains(Self::READ)
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.contains(Self::WRITE)
+    }
+
+    pub const fn is_executable(self) -> bool {
+        self.contains(Self::EXECUTE)
+    }
+
+    pub const fn is_user(self) -> bool {
+        self.contains(Self::USER)
+    }
+
+    pub const fn is_global(self) -> bool {
+        self.contains(Self::GLOBAL)
+    }
+
+    /// Check that these flags can go into a leaf. A leaf needs at least one
+    /// of R, W or X, and the RISC-V spec reserves W without R.
+    pub fn check_leaf(self) -> Result<PteFlags, PteError> {
+        if self.0 & Self::RWX == 0 {
+            Err(PteError::NoPermissions)
+        } else if self.is_writable() && !self.is_readable() {
+            Err(PteError::WriteWithoutRead)
+        } else {
+            Ok(self)
+        }
+    }
 }
 
-impl EntryBits {
-    pub fn val(self) -> i64 {
-        self as i64
+impl core::ops::BitOr for PteFlags {
+    type Output = PteFlags;
+
+    fn bitor(self, rhs: PteFlags) -> PteFlags {
+        PteFlags(self.0 | rhs.0)
     }
 }
 
-pub struct Entry {
-    pub entry: i64,
+impl core::ops::BitOrAssign for PteFlags {
+    fn bitor_assign(&mut self, rhs: PteFlags) {
+        self.0 |= rhs.0;
+    }
 }
 
-impl Entry {
-    pub fn is_valid(&self) -> bool {
-        self.get_entry() & EntryBits::Valid.val() != 0
+/// Prints the flags as RWXUGAD, with a '-' for every flag that is not set,
+/// followed by COW for copy-on-write pages.
+impl core::fmt::Display for PteFlags {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let letters = [
+            (PteFlags::READ, 'R'),
+            (PteFlags::WRITE, 'W'),
+            (PteFlags::EXECUTE, 'X'),
+            (PteFlags::USER, 'U'),
+            (PteFlags::GLOBAL, 'G'),
+            (PteFlags::ACCESS, 'A'),
+            (PteFlags::DIRTY, 'D'),
+        ];
+        for &(flag, letter) in letters.iter() {
+            let c = if self.contains(flag) { letter } else { '-' };
+            f.write_char(c)?;
+        }
+        if self.contains(PteFlags::COW) {
+            f.write_str(" COW")?;
+        }
+        Ok(())
+    }
+}
+
+/// Why a page table entry could not be built.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PteError {
+    /// The physical address is not aligned to a 4 KiB page.
+    Misaligned(usize),
+    /// The physical address does not fit into the 44-bit PPN.
+    OutOfRange(usize),
+    /// A leaf without any of R, W or X.
+    NoPermissions,
+    /// W without R, which RISC-V reserves.
+    WriteWithoutRead,
+}
+
+/// A single Sv39/Sv48 page table entry.
+///
+/// 63      54 53        10 9   8 7 6 5 4 3 2 1 0
+/// | reserved |    PPN    | RSW |D|A|G|U|X|W|R|V|
+#[repr(transparent)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Pte(u64);
+
+impl Pte {
+    const PPN_MASK: u64 = 0xfff_ffff_ffff;
+
+    /// An entry with V cleared. The hardware ignores everything else in it.
+    pub const fn invalid() -> Pte {
+        Pte(0)
+    }
+
+    /// Take an entry as it sits in memory, without any checks.
+    pub const fn from_bits(bits: u64) -> Pte {
+        Pte(bits)
+    }
+
+    fn checked_ppn(paddr: usize) -> Result<u64, PteError> {
+        if paddr % PAGE_SIZE != 0 {
+            Err(PteError::Misaligned(paddr))
+        } else if (paddr >> PAGE_ORDER) as u64 > Self::PPN_MASK {
+            Err(PteError::OutOfRange(paddr))
+        } else {
+            Ok((paddr >> PAGE_ORDER) as u64)
+        }
+    }
+
+    /// A valid leaf that translates to the page at `paddr`.
+    pub fn leaf(paddr: usize, flags: PteFlags) -> Result<Pte, PteError> {
+        let flags = flags.check_leaf()? | PteFlags::VALID;
+        Ok(Pte(Self::checked_ppn(paddr)? << 10 | flags.bits()))
+    }
+
+    /// A valid branch pointing at the next-level table at `paddr`.
+    pub fn branch(paddr: usize) -> Result<Pte, PteError> {
+        Ok(Pte(Self::checked_ppn(paddr)? << 10 | PteFlags::VALID.bits()))
+    }
+
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub const fn flags(self) -> PteFlags {
+        PteFlags(self.0 & PteFlags::MASK)
     }
 
-    pub fn is_invalid(&self) -> bool {
+    pub const fn ppn(self) -> usize {
+        ((self.0 >> 10) & Self::PPN_MASK) as usize
+    }
+
+    /// Address of the page (for a leaf) or table (for a branch).
+    pub const fn phys_addr(self) -> usize {
+        self.ppn() << PAGE_ORDER
+    }
+
+    /// The same entry with different flags. A leaf stays a leaf, so the
+    /// new flags are checked the same way leaf() checks them.
+    pub fn with_flags(self, flags: PteFlags) -> Result<Pte, PteError> {
+        Pte::leaf(self.phys_addr(), flags)
+    }
+
+    pub const fn is_valid(self) -> bool {
+        self.flags().contains(PteFlags::VALID)
+    }
+
+    pub const fn is_invalid(self) -> bool {
         !self.is_valid()
     }
 
-    pub fn is_leaf(&self) -> bool {
-        self.get_entry() & 0xe != 0
+    /// According to RISC-V, an entry with any of R, W or X set is a leaf,
+    /// no matter which level it is at.
+    pub const fn is_leaf(self) -> bool {
+        self.is_valid() && self.0 & PteFlags::RWX != 0
+    }
+
+    pub const fn is_branch(self) -> bool {
+        self.is_valid() && self.0 & PteFlags::RWX == 0
+    }
+
+    pub const fn is_readable(self) -> bool {
+        self.flags().is_readable()
+    }
+
+    pub const fn is_writable(self) -> bool {
+        self.flags().is_writable()
+    }
+
+    pub const fn is_executable(self) -> bool {
+        self.flags().is_executable()
+    }
+
+    pub const fn is_user(self) -> bool {
+        self.flags().is_user()
+    }
+
+    pub const fn is_global(self) -> bool {
+        self.flags().is_global()
     }
 
-    pub fn is_branch(&self) -> bool {
-        !self.is_leaf()
+    pub const fn is_accessed(self) -> bool {
+        self.flags().contains(PteFlags::ACCESS)
     }
 
-    pub fn set_entry(&mut self, entry: i64) {
-        self.entry = entry;
+    pub const fn is_dirty(self) -> bool {
+        self.flags().contains(PteFlags::DIRTY)
     }
 
-    pub fn get_entry(&self) -> i64 {
-        self.entry
+    /// An invalid entry for a page that was swapped out to slot. The
+    /// hardware ignores it, the slot goes where the PPN would be.
+    pub fn swapped(slot: usize) -> Pte {
+        assert!(slot as u64 <= Self::PPN_MASK);
+        Pte((slot as u64) << 10 | PteFlags::SWAPPED.bits())
+    }
+
+    pub const fn is_swapped(self) -> bool {
+        self.is_invalid() && self.flags().contains(PteFlags::SWAPPED)
+    }
+
+    pub const fn swap_slot(self) -> usize {
+        self.ppn()
     }
 }
 
 pub struct Table {
-    pub entries: [Entry; TABLE_SIZE],
+    pub entries: [Pte; TABLE_SIZE],
 }
 
 impl Table {
@@ -272,79 +1365,345 @@ impl Table {
     }
 }
 
-pub fn map(root: &mut Table, vaddr: usize, paddr: usize, bits: i64, level: usize) {
-    assert!(bits & 0xe != 0);
+/// Select Sv39 or Sv48 for all page tables. Call this before anything is
+/// mapped.
+pub fn set_paging_mode(mode: SatpMode) {
+    assert!(mode != SatpMode::Off);
+    unsafe {
+        PAGING_MODE = mode;
+    }
+}
+
+pub fn paging_mode() -> SatpMode {
+    unsafe { PAGING_MODE }
+}
 
-    // 0x1ff = xb1_1111_1111
-    let vpn = [
-        // VPN[0] = vaddr[20:12]
-        (vaddr >> 12) & 0x1ff,
-        // VPN[0] = vaddr[29:21]
-        (vaddr >> 21) & 0x1ff,
-        // VPN[0] = vaddr[38:30]
-        (vaddr >> 30) & 0x1ff,
-    ];
+/// Number of levels in a page table walk: 3 for Sv39 and 4 for Sv48. The
+/// root table is at level levels() - 1 and 4 KiB leaves are at level 0.
+pub fn levels() -> usize {
+    match paging_mode() {
+        SatpMode::Sv48 => 4,
+        _ => 3,
+    }
+}
 
-    let ppn = [
-        // VPN[0] = vaddr[20:12]
-        (paddr >> 12) & 0x1ff,
-        // VPN[0] = vaddr[29:21]
-        (paddr >> 21) & 0x1ff,
-        // VPN[0] = vaddr[55:30]
-        (paddr >> 30) & 0x3ff_ffff,
-    ];
+// Only the low 12 + 9 * levels() bits of a virtual address are translated
+// (39 for Sv39, 48 for Sv48). The bits above have to be copies of the top
+// translated bit.
+fn va_bits() -> usize {
+    PAGE_ORDER + 9 * levels()
+}
 
-    let mut v = &mut root.entries[vpn[2]];
+// VPN[level] is the 9-bit index into the table at that level:
+// vaddr[12 + 9 * level + 8 : 12 + 9 * level]
+// 0x1ff = xb1_1111_1111
+fn vpn(vaddr: usize, level: usize) -> usize {
+    (vaddr >> (PAGE_ORDER + 9 * level)) & 0x1ff
+}
 
-    for i in (level..2).rev() {
-        if !v.is_valid() {
-            let page = zalloc(1);
-            v.set_entry((page as i64 >> 2) | EntryBits::Valid.val());
+/// Size in bytes of a page mapped by a leaf at the given level. Level 0 is
+/// a regular 4 KiB page, level 1 a 2 MiB megapage, level 2 a 1 GiB gigapage
+/// and, under Sv48, level 3 a 512 GiB terapage.
+pub const fn level_size(level: usize) -> usize {
+    1 << (PAGE_ORDER + 9 * level)
+}
+
+// The table a branch entry points at, as the kernel sees it.
+fn child_table(v: &Pte) -> *mut Table {
+    phys_to_virt(v.phys_addr()) as *mut Table
+}
+
+// Free a table and every table hanging below it. `level` is the level of
+// the entries inside `table`, so leaves are never followed.
+fn free_table(table: *mut Table, level: usize) {
+    if level > 0 {
+        let table = unsafe { table.as_mut().unwrap() };
+        for entry in table.entries.iter() {
+            if entry.is_branch() {
+                free_table(child_table(entry), level - 1);
+            }
         }
-        let entry = ((v.get_entry() & !0x3ff) << 2) as *mut Entry;
-        v = unsafe { entry.add(vpn[i]).as_mut().unwrap() };
     }
+    dealloc(table as *mut u8);
+}
 
-    let entry = (ppn[2] << 28) as i64   // PPN[2] = [53:28]
-        | (ppn[1] << 19) as i64             // PPN[1] = [27:19]
-        | (ppn[0] << 10) as i64             // PPN[0] = [18:10]
-        | bits                              // Specified bits, such as User, Read, Write, etc
-        | EntryBits::Valid.val()            // Valid bit
-        | EntryBits::Dirty.val()            // Some machines require this to = 1
-        | EntryBits::Access.val(); // Just like dirty, some machines require this
-    v.set_entry(entry)
+// Turn a superpage leaf into a branch pointing at a fresh table of 512
+// leaves one level down, each with the same permissions. The translation
+// stays exactly the same, but parts of it can now be remapped. Fails if
+// there is no page for the table, and then leaves the leaf alone.
+fn split_leaf(v: &mut Pte, level: usize) -> Result<(), AllocError> {
+    let table = zalloc(1) as *mut Table;
+    if table.is_null() {
+        return Err(AllocError);
+    }
+    let entries = unsafe { &mut (*table).entries };
+    for (i, entry) in entries.iter_mut().enumerate() {
+        let addr = v.phys_addr() + i * level_size(level - 1);
+        *entry = Pte::leaf(addr, v.flags()).unwrap();
+    }
+    *v = Pte::branch(virt_to_phys(table as usize)).unwrap();
+    Ok(())
+}
+
+/// Map vaddr to paddr with a leaf at the given level (see level_size()).
+/// Both addresses have to be aligned to the size of that page. If the new
+/// leaf lands inside an existing superpage, the superpage is split first so
+/// the rest of it stays mapped. If it covers smaller mappings, the tables
+/// that held them are freed. Fails if a table on the way could not be
+/// allocated, in which case nothing is mapped, though tables that were
+/// already added stay.
+pub fn map(
+    root: &mut Table,
+    vaddr: usize,
+    paddr: usize,
+    flags: PteFlags,
+    level: usize,
+) -> Result<(), AllocError> {
+    assert!(level < levels());
+    assert!(
+        vaddr % level_size(level) == 0 && paddr % level_size(level) == 0,
+        "Unaligned superpage mapping 0x{:x} -> 0x{:x} at level {}",
+        vaddr,
+        paddr,
+        level
+    );
+    // Some machines require A and D to be set already, since they would
+    // fault instead of setting them on the first access. User leaves start
+    // out clean, though: the clock drops a clean page instead of swapping
+    // it out, and the first store gets D from vm::page_fault().
+    let mut flags = flags | PteFlags::ACCESS;
+    if !flags.is_user() {
+        flags |= PteFlags::DIRTY;
+    }
+    let leaf = match Pte::leaf(paddr, flags) {
+        Ok(leaf) => leaf,
+        Err(e) => panic!("Cannot map 0x{:x} -> 0x{:x}: {:?}", vaddr, paddr, e),
+    };
+
+    let top = levels() - 1;
+    let mut v = &mut root.entries[vpn(vaddr, top)];
+
+    for i in (level..top).rev() {
+        if v.is_invalid() {
+            let table = zalloc(1);
+            if table.is_null() {
+                return Err(AllocError);
+            }
+            *v = Pte::branch(virt_to_phys(table as usize)).unwrap();
+        } else if v.is_leaf() {
+            split_leaf(v, i + 1)?;
+        }
+        let table = child_table(v);
+        v = unsafe { &mut (*table).entries[vpn(vaddr, i)] };
+    }
+
+    if level > 0 && v.is_branch() {
+        free_table(child_table(v), level - 1);
+    }
+    *v = leaf;
+    Ok(())
 }
 
+/// Map [vaddr, vaddr + len) to [paddr, paddr + len) using the biggest page
+/// that both addresses are aligned to and that still fits, for each part
+/// of the range. A range that happens to cover a whole aligned 2 MiB or
+/// 1 GiB region takes a single entry for it. Fails like map(), with the
+/// part of the range before the failure left mapped.
+pub fn map_range(
+    root: &mut Table,
+    vaddr: usize,
+    paddr: usize,
+    len: usize,
+    flags: PteFlags,
+) -> Result<(), AllocError> {
+    assert!(vaddr % PAGE_SIZE == 0 && paddr % PAGE_SIZE == 0);
+    let end = vaddr + align_val(len, PAGE_ORDER);
+    let mut memaddr = vaddr;
+
+    while memaddr < end {
+        let phys = paddr + (memaddr - vaddr);
+        let mut level = levels() - 1;
+        while level > 0
+            && (memaddr % level_size(level) != 0
+                || phys % level_size(level) != 0
+                || memaddr + level_size(level) > end)
+        {
+            level -= 1;
+        }
+        map(root, memaddr, phys, flags, level)?;
+        memaddr += level_size(level);
+    }
+    Ok(())
+}
+
+/// Map the kernel addresses [start, end), rounded out to whole pages, to
+/// the physical memory behind them (see virt_to_phys()).
+pub fn map_kernel_range(
+    root: &mut Table,
+    start: usize,
+    end: usize,
+    flags: PteFlags,
+) -> Result<(), AllocError> {
+    let memaddr = start & !(PAGE_SIZE - 1);
+    map_range(
+        root,
+        memaddr,
+        virt_to_phys(memaddr),
+        align_val(end, PAGE_ORDER) - memaddr,
+        flags,
+    )
+}
+
+/// Tear down every table below the lower half of the root. The root itself
+/// belongs to the caller, so it is only cleared and not freed. The upper
+/// half is the kernel's and is shared with every other table, so it is
+/// left alone.
 pub fn unmap(root: &mut Table) {
-    for lv2 in 0..Table::len() {
-        let ref entry_lv2 = root.entries[lv2];
-        if entry_lv2.is_valid() && entry_lv2.is_branch() {
-            let memaddr_lv1 = (entry_lv2.get_entry() & !0x3ff) << 2;
-            let table_lv1 = unsafe { (memaddr_lv1 as *mut Table).as_mut().unwrap() };
-            for lv1 in 0..Table::len() {
-                let ref entry_lv1 = table_lv1.entries[lv1];
-                if entry_lv1.is_valid() && entry_lv1.is_branch() {
-                    let memaddr_lv0 = (entry_lv1.get_entry() & !0x3ff) << 2;
-                    dealloc(memaddr_lv0 as *mut u8);
-                }
-                dealloc(memaddr_lv1 as *mut u8);
+    for entry in root.entries[..KERNEL_HALF].iter_mut() {
+        if entry.is_branch() {
+            free_table(child_table(entry), levels() - 2);
+        }
+        *entry = Pte::invalid();
+    }
+}
+
+/// First address above the lower half of the address space. Everything
+/// below it is for user space.
+pub fn user_end() -> usize {
+    1 << (va_bits() - 1)
+}
+
+fn sign_extend(vaddr: usize) -> usize {
+    let shift = 64 - va_bits();
+    (((vaddr << shift) as isize) >> shift) as usize
+}
+
+// Clear the part of [first, last] that this table covers. `base` is the
+// (truncated to va_bits()) virtual address of the table's first entry.
+// Leaves that are only partly covered are split first. Child tables that
+// end up empty are freed, in which case `freed` is set. Returns true when
+// the table is now empty, and fails if a leaf could not be split.
+fn unmap_table(
+    table: &mut Table,
+    level: usize,
+    base: usize,
+    first: usize,
+    last: usize,
+    freed: &mut bool,
+) -> Result<bool, AllocError> {
+    for (i, v) in table.entries.iter_mut().enumerate() {
+        let lo = base + i * level_size(level);
+        let hi = lo + level_size(level) - 1;
+        if v.is_invalid() || hi < first || lo > last {
+            continue;
+        }
+        if v.is_leaf() || level == 0 {
+            if lo >= first && hi <= last {
+                *v = Pte::invalid();
+                cpu::satp_fence_vaddr(sign_extend(lo));
+                continue;
             }
+            split_leaf(v, level)?;
+        }
+        let child = child_table(v);
+        if unmap_table(
+            unsafe { child.as_mut().unwrap() },
+            level - 1,
+            lo,
+            first,
+            last,
+            freed,
+        )? {
+            dealloc(child as *mut u8);
+            *v = Pte::invalid();
+            *freed = true;
         }
     }
+    Ok(table.entries.iter().all(|entry| entry.is_invalid()))
 }
 
-pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
-    let vpn = [
-        // VPN[0] = vaddr[20:12]
-        (vaddr >> 12) & 0x1ff,
-        // VPN[1] = vaddr[29:21]
-        (vaddr >> 21) & 0x1ff,
-        // VPN[2] = vaddr[38:30]
-        (vaddr >> 30) & 0x1ff,
-    ];
+/// Unmap every page in [vaddr, vaddr + len). Superpages that stick out of
+/// the range are split so only the requested part goes away, and tables
+/// left without any valid entry are given back to the page allocator. The
+/// TLB is flushed for each removed leaf, and completely when a table was
+/// freed, since a cached branch may still point at it. Splitting needs a
+/// page for the new table; without one this fails, with only part of the
+/// range unmapped.
+pub fn unmap_range(root: &mut Table, vaddr: usize, len: usize) -> Result<(), AllocError> {
+    assert!(vaddr % PAGE_SIZE == 0);
+    if len == 0 {
+        return Ok(());
+    }
+    let mask = (1 << va_bits()) - 1;
+    let first = vaddr & mask;
+    let last = (align_val(vaddr + len, PAGE_ORDER) - 1) & mask;
+    assert!(first <= last, "Range crosses the virtual address hole");
 
-    let mut v = &root.entries[vpn[2]];
-    for i in (0..=2).rev() {
+    let mut freed = false;
+    let result = unmap_table(root, levels() - 1, 0, first, last, &mut freed);
+    if freed {
+        cpu::satp_fence_all();
+    }
+    result.map(|_| ())
+}
+
+/// Give root a branch in every entry that [start, end) falls into, so
+/// tables that copy the kernel's root entries see whatever is mapped there
+/// later, too.
+pub fn populate_root(root: &mut Table, start: usize, end: usize) {
+    let top = levels() - 1;
+    let mut vaddr = start & !(level_size(top) - 1);
+    while vaddr < end {
+        let v = &mut root.entries[vpn(vaddr, top)];
+        assert!(!v.is_leaf(), "0x{:x} is already mapped", vaddr);
+        if v.is_invalid() {
+            let table = zalloc(1);
+            assert!(!table.is_null(), "Out of memory for a page table");
+            *v = Pte::branch(virt_to_phys(table as usize)).unwrap();
+        }
+        vaddr += level_size(top);
+    }
+}
+
+/// Find the leaf that translates vaddr, together with the level it sits
+/// at. Returns None if any entry on the way is invalid.
+pub fn find_leaf(root: &mut Table, vaddr: usize) -> Option<(&mut Pte, usize)> {
+    let mut table = root as *mut Table;
+    for level in (0..levels()).rev() {
+        let v = unsafe { &mut (*table).entries[vpn(vaddr, level)] };
+        if v.is_leaf() {
+            return Some((v, level));
+        } else if !v.is_branch() || level == 0 {
+            break;
+        }
+        table = child_table(v);
+    }
+    None
+}
+
+/// Find the entry where a walk for vaddr stops: a leaf, or an invalid
+/// entry in a table that exists, which may record a swapped out page.
+/// Returns None if the walk runs into a malformed branch at level 0.
+pub fn find_entry(root: &mut Table, vaddr: usize) -> Option<(&mut Pte, usize)> {
+    let mut table = root as *mut Table;
+    for level in (0..levels()).rev() {
+        let v = unsafe { &mut (*table).entries[vpn(vaddr, level)] };
+        if !v.is_branch() {
+            return Some((v, level));
+        } else if level == 0 {
+            break;
+        }
+        table = child_table(v);
+    }
+    None
+}
+
+/// Walk the table rooted at root to find the physical address that vaddr
+/// translates to.
+pub fn translate(root: &Table, vaddr: usize) -> Option<usize> {
+    let top = levels() - 1;
+    let mut v = root.entries[vpn(vaddr, top)];
+    for i in (0..=top).rev() {
         if v.is_invalid() {
             // This is an invalid entry, page fault.
             break;
@@ -360,15 +1719,256 @@ pub fn virt_to_phys(root: &Table, vaddr: usize) -> Option<usize> {
             // off_mask = 1_0000_0000_0000 - 1 = 1111_1111_1111
             let off_mask = (1 << (12 + i * 9)) - 1;
             let vaddr_pgoff = vaddr & off_mask;
-            let addr = ((v.get_entry() << 2) as usize) & !off_mask;
+            let addr = v.phys_addr() & !off_mask;
             return Some(addr | vaddr_pgoff);
+        } else if i == 0 {
+            // A branch at the last level is malformed, treat it like a
+            // page fault.
+            break;
         }
 
-        // 0x3ff = 0011_1111_1111
-        // '!'0x3ff = 1100_0000_0000
-        let entry = ((v.get_entry() & !0x3ff) << 2) as *const Entry;
-        v = unsafe { entry.add(vpn[i - 1]).as_ref().unwrap() };
+        let table = child_table(&v);
+        v = unsafe { (*table).entries[vpn(vaddr, i - 1)] };
     }
 
     None
 }
+
+// ///////////////////////////////////
+// / DEBUGGING
+// ///////////////////////////////////
+
+/// A run of leaves of the same size and flags that map consecutive virtual
+/// pages to consecutive physical pages.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Mapping {
+    pub vaddr: usize,
+    pub paddr: usize,
+    pub len: usize,
+    /// Level of the leaves, see level_size().
+    pub level: usize,
+    pub flags: PteFlags,
+}
+
+impl Mapping {
+    pub fn end(&self) -> usize {
+        self.vaddr + self.len
+    }
+
+    // The part of the run from vaddr on.
+    fn from(self, vaddr: usize) -> Mapping {
+        let skip = vaddr - self.vaddr;
+        Mapping {
+            vaddr,
+            paddr: self.paddr + skip,
+            len: self.len - skip,
+            ..self
+        }
+    }
+
+    // The first len bytes of the run.
+    fn take(self, len: usize) -> Mapping {
+        Mapping { len, ..self }
+    }
+}
+
+impl core::fmt::Display for Mapping {
+    fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+        let size = match self.level {
+            0 => "4K",
+            1 => "2M",
+            2 => "1G",
+            _ => "512G",
+        };
+        write!(
+            f,
+            "0x{:016x}-0x{:016x} -> 0x{:x} {:>4} {}",
+            self.vaddr,
+            self.end() - 1,
+            self.paddr,
+            size,
+            self.flags
+        )
+    }
+}
+
+/// Iterator over the leaves of a table tree in virtual address order, with
+/// neighbouring leaves merged into one Mapping where they continue each
+/// other. Walking needs no memory, so it works on any table at any time.
+pub struct Mappings<'a> {
+    // The walk borrows the tree, even though it only holds raw pointers.
+    root: PhantomData<&'a Table>,
+    // The table and the index in it for each level of the walk. Only the
+    // levels from `level` up to the root are valid.
+    tables: [*const Table; 4],
+    index: [usize; 4],
+    level: usize,
+    pending: Option<Mapping>,
+}
+
+/// Walk all mappings of the table tree rooted at root.
+pub fn mappings(root: &Table) -> Mappings {
+    let top = levels() - 1;
+    let mut tables = [null_mut() as *const Table; 4];
+    tables[top] = root;
+    Mappings {
+        root: PhantomData,
+        tables,
+        index: [0; 4],
+        level: top,
+        pending: None,
+    }
+}
+
+impl<'a> Mappings<'a> {
+    // The next single leaf.
+    fn next_leaf(&mut self) -> Option<Mapping> {
+        let top = levels() - 1;
+        loop {
+            let level = self.level;
+            if self.index[level] == TABLE_SIZE {
+                if level == top {
+                    return None;
+                }
+                self.level += 1;
+                self.index[self.level] += 1;
+                continue;
+            }
+            let v = unsafe { (*self.tables[level]).entries[self.index[level]] };
+            if v.is_leaf() {
+                let mut vaddr = 0;
+                for l in level..=top {
+                    vaddr |= self.index[l] << (PAGE_ORDER + 9 * l);
+                }
+                self.index[level] += 1;
+                return Some(Mapping {
+                    vaddr: sign_extend(vaddr),
+                    paddr: v.phys_addr(),
+                    len: level_size(level),
+                    level,
+                    flags: v.flags(),
+                });
+            } else if v.is_branch() && level > 0 {
+                self.level -= 1;
+                self.tables[self.level] = child_table(&v);
+                self.index[self.level] = 0;
+            } else {
+                self.index[level] += 1;
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Mappings<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut run = self.pending.take().or_else(|| self.next_leaf())?;
+        while let Some(leaf) = self.next_leaf() {
+            if leaf.vaddr == run.end()
+                && leaf.paddr == run.paddr + run.len
+                && leaf.level == run.level
+                && leaf.flags == run.flags
+            {
+                run.len += leaf.len;
+            } else {
+                self.pending = Some(leaf);
+                break;
+            }
+        }
+        Some(run)
+    }
+}
+
+/// Print every mapping of the table tree rooted at root, one line per run
+/// of leaves: virtual range, physical start, page size and flags.
+pub fn print_mappings(root: &Table) {
+    println!();
+    println!("PAGE TABLE AT {:p} ({:?})", root, paging_mode());
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    let mut total = 0;
+    for mapping in mappings(root) {
+        println!("{}", mapping);
+        total += mapping.len;
+    }
+    println!("~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~~");
+    println!("Mapped: {} bytes.", total);
+    println!();
+}
+
+/// Panic if anything root maps is both writable and executable. kinit
+/// runs this over the kernel's table before switching to it.
+pub fn audit_wx(root: &Table) {
+    for mapping in mappings(root) {
+        if mapping.flags.is_writable() && mapping.flags.is_executable() {
+            panic!("W^X violation: {}", mapping);
+        }
+    }
+}
+
+/// Print what differs between two table trees: '-' for ranges that only
+/// old maps, '+' for ranges that only new maps, and '~' for ranges that
+/// both map, but to a different place or with different flags. Ranges that
+/// translate the same way are left out, even if the page size changed.
+/// Returns the number of lines printed, so 0 means no difference.
+pub fn print_mapping_diff(old: &Table, new: &Table) -> usize {
+    let mut lines = 0;
+    let (mut olds, mut news) = (mappings(old), mappings(new));
+    let (mut a, mut b) = (olds.next(), news.next());
+    loop {
+        match (a, b) {
+            (None, None) => break,
+            (Some(x), None) => {
+                println!("- {}", x);
+                lines += 1;
+                a = olds.next();
+            }
+            (None, Some(y)) => {
+                println!("+ {}", y);
+                lines += 1;
+                b = news.next();
+            }
+            (Some(x), Some(y)) => {
+                if x.end() <= y.vaddr {
+                    println!("- {}", x);
+                    lines += 1;
+                    a = olds.next();
+                } else if y.end() <= x.vaddr {
+                    println!("+ {}", y);
+                    lines += 1;
+                    b = news.next();
+                } else if x.vaddr < y.vaddr {
+                    // Only the head of x sticks out.
+                    println!("- {}", x.take(y.vaddr - x.vaddr));
+                    lines += 1;
+                    a = Some(x.from(y.vaddr));
+                } else if y.vaddr < x.vaddr {
+                    println!("+ {}", y.take(x.vaddr - y.vaddr));
+                    lines += 1;
+                    b = Some(y.from(x.vaddr));
+                } else {
+                    // Both start here. Compare the part they have in common
+                    // and go on with whatever is left of the longer one.
+                    let len = x.len.min(y.len);
+                    if x.paddr != y.paddr || x.flags != y.flags {
+                        println!("~ {}", x.take(len));
+                        println!("  {}", y.take(len));
+                        lines += 1;
+                    }
+                    let end = x.vaddr + len;
+                    a = if x.end() > end {
+                        Some(x.from(end))
+                    } else {
+                        olds.next()
+                    };
+                    b = if y.end() > end {
+                        Some(y.from(end))
+                    } else {
+                        news.next()
+                    };
+                }
+            }
+        }
+    }
+    lines
+}
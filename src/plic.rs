@@ -1,17 +1,45 @@
+use crate::page::phys_to_virt;
 use crate::println;
 
-const PLIC_PRIORITY: usize = 0x0c00_0000;
-const PLIC_PENDING: usize = 0x0c00_1000;
-const PLIC_INT_ENABLE: usize = 0x0c00_2000;
-const PLIC_THRESHOLD: usize = 0x0c20_0000;
-const PLIC_CLAIM: usize = 0x0c20_0004;
+// Register offsets from the PLIC base. The enable, threshold and claim
+// registers are the ones of context 1 (hart 0, supervisor mode), since
+// that is where we handle interrupts.
+const PLIC_PRIORITY: usize = 0x0;
+const PLIC_PENDING: usize = 0x1000;
+const PLIC_INT_ENABLE: usize = 0x2080;
+const PLIC_THRESHOLD: usize = 0x20_1000;
+const PLIC_CLAIM: usize = 0x20_1004;
+
+// Where the PLIC sits on the QEMU virt machine. probe() replaces these with
+// what the device tree says.
+static mut PLIC_BASE: usize = 0x0c00_0000;
+static mut PLIC_SIZE: usize = 0x60_0000;
+
+/// Look the PLIC up in the device tree.
+pub fn probe() {
+    if let Some((base, size)) = crate::fdt::find_device(&["riscv,plic0", "sifive,plic-1.0.0"]) {
+        unsafe {
+            PLIC_BASE = base;
+            PLIC_SIZE = size;
+        }
+    }
+}
+
+/// The MMIO range of the PLIC as (base, size).
+pub fn mmio_range() -> (usize, usize) {
+    unsafe { (PLIC_BASE, PLIC_SIZE) }
+}
+
+fn reg(offset: usize) -> usize {
+    unsafe { phys_to_virt(PLIC_BASE + offset) }
+}
 
 /// Get the next available interrupt. This is the "claim" process.
 /// The plic will automatically sort by priority and hand us the
 /// ID of the interrupt. For example, if the UART is interrupting
 /// and it's next, we will get the value 10.
 pub fn next() -> Option<u32> {
-    let claim_reg = PLIC_CLAIM as *const u32;
+    let claim_reg = reg(PLIC_CLAIM) as *const u32;
     let claim_no;
     unsafe {
         claim_no = claim_reg.read_volatile();
@@ -29,7 +57,7 @@ pub fn next() -> Option<u32> {
 /// Complete a pending interrupt by id. The id should come
 /// from the next() function above.
 pub fn complete(id: u32) {
-    let complete_reg = PLIC_CLAIM as *mut u32;
+    let complete_reg = reg(PLIC_CLAIM) as *mut u32;
     unsafe {
         // We actually write a u32 into the entire complete_register.
         // This is the same register as the claim register, but it can
@@ -40,7 +68,7 @@ pub fn complete(id: u32) {
 
 /// Enable a given interrupt id
 pub fn enable(id: u32) {
-    let enables = PLIC_INT_ENABLE as *mut u32;
+    let enables = reg(PLIC_INT_ENABLE) as *mut u32;
     let actual_id = 1 << id;
     unsafe {
         // Unlike the complete and claim registers, the plic_int_enable
@@ -55,7 +83,7 @@ pub fn enable(id: u32) {
 /// The priority must be [0..7]
 pub fn set_priority(id: u32, prio: u8) {
     let actual_prio = prio as u32 & 7;
-    let prio_reg = PLIC_PRIORITY as *mut u32;
+    let prio_reg = reg(PLIC_PRIORITY) as *mut u32;
     unsafe {
         // The offset for the interrupt id is:
         // PLIC_PRIORITY + 4 * id
@@ -74,7 +102,7 @@ pub fn set_threshold(tsh: u8) {
     // is a 3-bit 0b111. So, we and with 7 (0b111) to just get the
     // last three bits.
     let actual_tsh = tsh & 7;
-    let tsh_reg = PLIC_THRESHOLD as *mut u32;
+    let tsh_reg = reg(PLIC_THRESHOLD) as *mut u32;
     unsafe {
         tsh_reg.write_volatile(actual_tsh as u32);
     }
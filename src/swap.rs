@@ -0,0 +1,260 @@
+// Swapping anonymous user pages out to the block device.
+//
+// The swap area sits in the second half of the disk, leaving the first
+// half to whatever file system ends up there. It starts at the first page
+// boundary past the middle, with a header page in the format of Linux's
+// mkswap (version 1), so a swap file made with `mkswap` can be written
+// there with dd. Without that header, the disk holds something else and is
+// left alone. The pages after the header are page-sized slots, and every
+// slot has a reference count: fork() shares swapped out pages just like it
+// shares resident ones, so more than one entry may record the same slot.
+//
+// page::clock_victim() decides what goes out. The page's entry is replaced
+// by Pte::swapped(slot), and the next access to it faults the page back in
+// through vm::page_fault(). A page that was never written since it was
+// faulted in does not need a slot: it is the same as what the next fault
+// makes anyway (zeroes, or the pager's data), so it is simply dropped.
+//
+// Any hart may run out of memory and reclaim, so SWAP_LOCK guards the
+// slots and the block device, which nobody else uses. reclaim() holds it
+// from picking a victim until the victim's entry is updated, so two harts
+// never go for the same page.
+
+use crate::block::{self, SECTOR_SIZE};
+use crate::lock::IrqLock;
+use crate::oom::{self, Reclaim};
+use crate::page::{
+    clock_victim, dealloc, put_page, set_swappable, swappable_pages, virt_to_phys, zalloc, Pte,
+    PteFlags, PAGE_SIZE,
+};
+use crate::vm::FaultError;
+use crate::{cpu, println};
+use alloc::vec::Vec;
+use core::{convert::TryInto, slice};
+
+const SECTORS_PER_SLOT: u64 = (PAGE_SIZE / SECTOR_SIZE) as u64;
+
+// The header page ends in the magic. The version and the index of the last
+// usable page are at these offsets.
+const SWAP_MAGIC: &[u8] = b"SWAPSPACE2";
+const HDR_VERSION: usize = 1024;
+const HDR_LAST_PAGE: usize = 1028;
+
+struct SwapArea {
+    // First sector of the area, which holds the header.
+    start: u64,
+    // Reference count of every slot, 0 if it is free.
+    refs: Vec<u16>,
+    free: usize,
+    // Where to start looking for a free slot.
+    hint: usize,
+}
+
+static mut SWAP: Option<SwapArea> = None;
+static SWAP_LOCK: IrqLock<()> = IrqLock::new(());
+
+// Number of slots the header page promises, if it is one.
+fn read_header(header: &[u8]) -> Option<usize> {
+    let word = |off: usize| u32::from_le_bytes(header[off..off + 4].try_into().unwrap());
+    if !header.ends_with(SWAP_MAGIC) || word(HDR_VERSION) != 1 {
+        return None;
+    }
+    Some(word(HDR_LAST_PAGE) as usize)
+}
+
+/// Set up the swap area on the block device. Call this after block::init().
+/// Returns false, and says why, if there is nothing to swap to.
+pub fn init() -> bool {
+    let capacity = block::capacity();
+    let start = (capacity / 2 + SECTORS_PER_SLOT - 1) / SECTORS_PER_SLOT * SECTORS_PER_SLOT;
+    if start + SECTORS_PER_SLOT > capacity {
+        println!("Swap: the disk is too small");
+        return false;
+    }
+    let header = zalloc(1);
+    if header.is_null() {
+        println!("Swap: no memory for the header");
+        return false;
+    }
+    let read = {
+        let _lock = SWAP_LOCK.lock();
+        block::read(start, header, PAGE_SIZE)
+    };
+    let promised = if read.is_ok() {
+        read_header(unsafe { slice::from_raw_parts(header, PAGE_SIZE) })
+    } else {
+        None
+    };
+    dealloc(header);
+    let promised = match promised {
+        Some(promised) => promised,
+        None => {
+            println!("Swap: no swap header at sector {}, not swapping", start);
+            return false;
+        }
+    };
+    // Never trust the header with more than the disk has.
+    let slots = promised.min(((capacity - start) / SECTORS_PER_SLOT - 1) as usize);
+    if slots == 0 {
+        println!("Swap: the area at sector {} is empty", start);
+        return false;
+    }
+    // Allocating may reclaim, which takes SWAP_LOCK, so the area is made
+    // before we take it.
+    let area = SwapArea {
+        start,
+        refs: alloc::vec![0; slots],
+        free: slots,
+        hint: 0,
+    };
+    let _lock = SWAP_LOCK.lock();
+    unsafe {
+        SWAP = Some(area);
+    }
+    println!("Swap: {} KiB at sector {}", slots * PAGE_SIZE / 1024, start);
+    true
+}
+
+/// Number of (total, free) slots.
+pub fn slots() -> (usize, usize) {
+    let _lock = SWAP_LOCK.lock();
+    unsafe {
+        SWAP.as_ref()
+            .map_or((0, 0), |swap| (swap.refs.len(), swap.free))
+    }
+}
+
+fn sector(swap: &SwapArea, slot: usize) -> u64 {
+    // Slots start behind the header page.
+    swap.start + (slot as u64 + 1) * SECTORS_PER_SLOT
+}
+
+fn alloc_slot(swap: &mut SwapArea) -> Option<usize> {
+    if swap.free == 0 {
+        return None;
+    }
+    let n = swap.refs.len();
+    let slot = (0..n)
+        .map(|i| (swap.hint + i) % n)
+        .find(|&slot| swap.refs[slot] == 0)?;
+    swap.refs[slot] = 1;
+    swap.free -= 1;
+    swap.hint = (slot + 1) % n;
+    Some(slot)
+}
+
+/// Take another reference to slot, for an entry that was copied.
+pub fn get_slot(slot: usize) {
+    let _lock = SWAP_LOCK.lock();
+    let swap = unsafe { SWAP.as_mut().expect("get_slot without a swap area") };
+    assert!(swap.refs[slot] > 0, "get_slot on free slot {}", slot);
+    assert!(
+        swap.refs[slot] < u16::MAX,
+        "Swap slot reference count overflow"
+    );
+    swap.refs[slot] += 1;
+}
+
+/// Drop a reference to slot, freeing it with the last one.
+pub fn put_slot(slot: usize) {
+    let _lock = SWAP_LOCK.lock();
+    let swap = unsafe { SWAP.as_mut().expect("put_slot without a swap area") };
+    release_slot(swap, slot);
+}
+
+// put_slot() for callers that hold SWAP_LOCK already.
+fn release_slot(swap: &mut SwapArea, slot: usize) {
+    assert!(swap.refs[slot] > 0, "put_slot on free slot {}", slot);
+    swap.refs[slot] -= 1;
+    if swap.refs[slot] == 0 {
+        swap.free += 1;
+    }
+}
+
+/// Free up to pages pages of user memory, picked by the clock. Clean
+/// pages are dropped and dirty ones swapped out. Returns how many were
+/// freed, which is less when the clock has gone around once without
+/// finding enough, e.g. because there is nowhere to swap to.
+pub fn reclaim(pages: usize) -> usize {
+    let _lock = SWAP_LOCK.lock();
+    let mut swap = unsafe { SWAP.as_mut() };
+    let mut count = 0;
+    for _ in 0..swappable_pages() {
+        if count == pages {
+            break;
+        }
+        let (page, pte) = match clock_victim() {
+            Some(victim) => victim,
+            None => break,
+        };
+        let old = unsafe { *pte };
+        let slot = if old.is_dirty() {
+            // The clock has moved on, so skipping the page moves on to
+            // the next one.
+            let swap = match swap.as_mut() {
+                Some(swap) => swap,
+                None => continue,
+            };
+            match alloc_slot(swap) {
+                Some(slot) => Some(slot),
+                None => continue,
+            }
+        } else {
+            None
+        };
+        // The mapping goes first, so no store can slip in behind the copy
+        // we write out, and nothing reaches the page once it is free. The
+        // entry may be in any address space.
+        unsafe {
+            *pte = slot.map_or(Pte::invalid(), Pte::swapped);
+        }
+        cpu::satp_fence_all();
+        if let Some(slot) = slot {
+            let swap = swap.as_mut().unwrap();
+            if block::write(sector(swap, slot), page as *const u8, PAGE_SIZE).is_err() {
+                // The page is still there, so it simply stays mapped.
+                unsafe {
+                    *pte = old;
+                }
+                release_slot(swap, slot);
+                break;
+            }
+        }
+        // This takes the page off the clock list and frees it.
+        put_page(page);
+        count += 1;
+    }
+    count
+}
+
+/// Allocate a zeroed page for user memory, reclaiming memory for it if we
+/// have to, up to killing another address space. Returns null if nothing
+/// helps.
+pub fn alloc_page() -> *mut u8 {
+    oom::retry(Reclaim::Kill, 1, || zalloc(1))
+}
+
+/// Bring the page that entry says was swapped out back in, and map it
+/// there again with flags.
+pub fn swap_in(entry: &mut Pte, flags: PteFlags) -> Result<(), FaultError> {
+    assert!(entry.is_swapped());
+    let slot = entry.swap_slot();
+    let page = alloc_page();
+    if page.is_null() {
+        return Err(FaultError::OutOfMemory);
+    }
+    // Not before the page is there, since alloc_page() may reclaim.
+    let _lock = SWAP_LOCK.lock();
+    let swap = unsafe { SWAP.as_mut().expect("Swapped out page without a swap area") };
+    if block::read(sector(swap, slot), page, PAGE_SIZE).is_err() {
+        dealloc(page);
+        return Err(FaultError::IoError);
+    }
+    release_slot(swap, slot);
+    // The slot is gone, so this is the only copy now and has to be written
+    // out again before it can go.
+    let flags = flags | PteFlags::ACCESS | PteFlags::DIRTY;
+    *entry = Pte::leaf(virt_to_phys(page as usize), flags).unwrap();
+    set_swappable(page as usize, entry);
+    Ok(())
+}
@@ -1,10 +1,10 @@
 use crate::cpu::TrapFrame;
 use crate::plic::complete;
-use crate::{plic, uart};
+use crate::{clint, cpu, plic, uart, vm};
 use crate::{print, println};
 
 #[no_mangle]
-extern "C" fn m_trap(
+extern "C" fn s_trap(
     epc: usize,
     tval: usize,
     cause: usize,
@@ -12,9 +12,10 @@ extern "C" fn m_trap(
     _status: usize,
     _frame: &mut TrapFrame,
 ) -> usize {
-    // We're going to handle all traps in machine mode. RISC-V lets
-    // us delegate to supervisor mode, but switching out SATP (virtual memory)
-    // gets hairy.
+    // Machine mode delegates every trap to us except for the CLINT's
+    // interrupts, which m_trap_vector turns into their supervisor versions.
+    // Since the kernel lives in the upper half of every address space, we
+    // can take a trap without switching tables.
     let is_async = {
         if cause >> 63 & 1 == 1 {
             true
@@ -31,24 +32,23 @@ extern "C" fn m_trap(
     if is_async {
         // Asynchronous trap
         match cause_num {
-            3 => {
-                // Machine software
-                println!("Machine software interrupt CPU#{}", hart);
+            1 => {
+                // Supervisor software
+                println!("Supervisor software interrupt CPU#{}", hart);
+                cpu::sip_clear(1 << 1);
             }
-            7 => unsafe {
-                // Machine timer
-                let mtimecmp = 0x0200_4000 as *mut u64;
-                let mtime = 0x0200_bff8 as *const u64;
+            5 => {
+                // Supervisor timer
                 // The frequency given by QEMU is 10_000_000 Hz, so this sets
                 // the next interrupt to fire one second from now.
-                mtimecmp.write_volatile(mtime.read_volatile() + 10_000_000);
-            },
-            11 => {
-                // Machine external (interrupt from Platform Interrupt Controller (PLIC))
+                clint::set_timer(clint::mtime() + 10_000_000);
+            }
+            9 => {
+                // Supervisor external (interrupt from Platform Interrupt Controller (PLIC))
                 if let Some(interrupt) = plic::next() {
                     match interrupt {
-                        10 => {
-                            let mut uart = uart::Uart::new(0x1000_0000);
+                        irq if irq == uart::irq() => {
+                            let mut uart = uart::Uart::new(uart::base_address());
                             if let Some(c) = uart.get() {
                                 match c {
                                     8 | 127 => {
@@ -93,39 +93,23 @@ extern "C" fn m_trap(
                 println!("E-call from User mode! CPU#{} -> 0x{:08x}", hart, epc);
                 return_pc += 4;
             }
-            9 => {
-                // Environment (system) call from Supervisor mode
-                println!("E-call from Supervisor mode! CPU#{} -> 0x{:08x}", hart, epc);
-                return_pc += 4;
-            }
-            11 => {
-                // Environment (system) call from Machine mode
-                println!("E-call from Machine mode! CPU#{} -> 0x{:08x}", hart, epc);
-                return_pc += 4;
-            }
-            12 => {
-                // Instruction page fault
-                println!(
-                    "Instruction page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
-                return_pc += 4;
-            }
-            13 => {
-                // Load page fault
-                println!(
-                    "Load page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
-                return_pc += 4;
-            }
-            15 => {
-                // Store page fault
-                println!(
-                    "Store page fault CPU#{} -> 0x{:08x}: 0x{:08x}",
-                    hart, epc, tval
-                );
-                return_pc += 4;
+            12 | 13 | 15 => {
+                // 12 = Instruction page fault
+                // 13 = Load page fault
+                // 15 = Store page fault
+                let access = match cause_num {
+                    12 => vm::Access::Fetch,
+                    13 => vm::Access::Load,
+                    _ => vm::Access::Store,
+                };
+                // If the handler mapped the page, we return to the same
+                // instruction so it runs again, this time successfully.
+                if let Err(e) = vm::page_fault(tval, access) {
+                    panic!(
+                        "{:?} page fault CPU#{} -> 0x{:08x}: 0x{:08x} ({:?})\n",
+                        access, hart, epc, tval, e
+                    );
+                }
             }
             _ => {
                 panic!("Unhandled sync trap CPU#{} -> {}\n", hart, cause_num);
@@ -1,8 +1,45 @@
+use crate::page::phys_to_virt;
 use core::{
     convert::TryInto,
     fmt::{Error, Write},
 };
 
+// Where the NS16550A sits on the QEMU virt machine, and its PLIC
+// interrupt. probe() replaces these with what the device tree says.
+static mut UART_BASE: usize = 0x1000_0000;
+static mut UART_SIZE: usize = 0x100;
+static mut UART_IRQ: u32 = 10;
+
+/// Look the UART up in the device tree.
+pub fn probe() {
+    if let Some((base, size)) = crate::fdt::find_device(&["ns16550a"]) {
+        unsafe {
+            UART_BASE = base;
+            UART_SIZE = size;
+        }
+    }
+    if let Some(irq) = crate::fdt::find_interrupt(&["ns16550a"]) {
+        unsafe {
+            UART_IRQ = irq;
+        }
+    }
+}
+
+/// The PLIC interrupt the UART raises.
+pub fn irq() -> u32 {
+    unsafe { UART_IRQ }
+}
+
+/// The MMIO range of the UART as (base, size).
+pub fn mmio_range() -> (usize, usize) {
+    unsafe { (UART_BASE, UART_SIZE) }
+}
+
+/// Where the kernel reaches the UART registers.
+pub fn base_address() -> usize {
+    unsafe { phys_to_virt(UART_BASE) }
+}
+
 pub struct Uart {
     base_address: usize,
 }
@@ -119,6 +156,6 @@ macro_rules! println {
 
 #[doc(hidden)]
 pub fn _print(args: core::fmt::Arguments) {
-    let mut uart = Uart::new(0x1000_0000);
+    let mut uart = Uart::new(base_address());
     uart.write_fmt(args).unwrap();
 }
@@ -0,0 +1,300 @@
+// Virtio devices on the MMIO transport, which is what the QEMU virt
+// machine offers: eight slots, 0x1000 bytes apart, starting at 0x1000_1000.
+// Empty slots still answer with the magic value, but with device ID 0.
+//
+// Both the legacy (version 1) and the modern (version 2) register layout
+// are handled. Each device gets a single virtqueue, and requests are
+// polled for one at a time. That is slow, but it works from the page fault
+// handler, which runs with interrupts off.
+
+use crate::page::{alloc_aligned, phys_to_virt, DmaBuffer, PAGE_SIZE};
+use core::mem::size_of;
+use core::sync::atomic::{fence, Ordering};
+
+// Register offsets from the base of a device.
+const MAGIC: usize = 0x000;
+const VERSION: usize = 0x004;
+const DEVICE_ID: usize = 0x008;
+const DEVICE_FEATURES: usize = 0x010;
+const DEVICE_FEATURES_SEL: usize = 0x014;
+const DRIVER_FEATURES: usize = 0x020;
+const DRIVER_FEATURES_SEL: usize = 0x024;
+const GUEST_PAGE_SIZE: usize = 0x028;
+const QUEUE_SEL: usize = 0x030;
+const QUEUE_NUM_MAX: usize = 0x034;
+const QUEUE_NUM: usize = 0x038;
+const QUEUE_ALIGN: usize = 0x03c;
+const QUEUE_PFN: usize = 0x040;
+const QUEUE_READY: usize = 0x044;
+const QUEUE_NOTIFY: usize = 0x050;
+const INTERRUPT_STATUS: usize = 0x060;
+const INTERRUPT_ACK: usize = 0x064;
+const STATUS: usize = 0x070;
+const QUEUE_DESC_LOW: usize = 0x080;
+const QUEUE_DESC_HIGH: usize = 0x084;
+const QUEUE_DRIVER_LOW: usize = 0x090;
+const QUEUE_DRIVER_HIGH: usize = 0x094;
+const QUEUE_DEVICE_LOW: usize = 0x0a0;
+const QUEUE_DEVICE_HIGH: usize = 0x0a4;
+/// The device specific configuration space starts here.
+pub const CONFIG: usize = 0x100;
+
+// "virt" in little endian
+const MAGIC_VALUE: u32 = 0x7472_6976;
+
+// Device status bits
+const STATUS_ACKNOWLEDGE: u32 = 1;
+const STATUS_DRIVER: u32 = 2;
+const STATUS_DRIVER_OK: u32 = 4;
+const STATUS_FEATURES_OK: u32 = 8;
+const STATUS_FAILED: u32 = 128;
+
+// VIRTIO_F_VERSION_1, bit 0 of the second feature word. A modern device
+// refuses a driver that does not accept it.
+const FEATURE_VERSION_1: u32 = 1;
+
+/// Device IDs
+pub const DEVICE_BLOCK: u32 = 2;
+
+/// The next field of the descriptor is valid.
+pub const DESC_NEXT: u16 = 1;
+/// The device writes into the buffer, rather than reading from it.
+pub const DESC_WRITE: u16 = 2;
+
+// Plenty for one request at a time, and it keeps the descriptors and the
+// available ring within the first page of the queue.
+const QUEUE_SIZE: usize = 8;
+
+// Where the slots are on the QEMU virt machine. probe() replaces these
+// with what the device tree says.
+const MAX_DEVICES: usize = 8;
+static mut DEVICES: [(usize, usize); MAX_DEVICES] = [
+    (0x1000_1000, 0x1000),
+    (0x1000_2000, 0x1000),
+    (0x1000_3000, 0x1000),
+    (0x1000_4000, 0x1000),
+    (0x1000_5000, 0x1000),
+    (0x1000_6000, 0x1000),
+    (0x1000_7000, 0x1000),
+    (0x1000_8000, 0x1000),
+];
+static mut DEVICE_COUNT: usize = MAX_DEVICES;
+
+/// Look the virtio slots up in the device tree.
+pub fn probe() {
+    let fdt = match crate::fdt::get() {
+        Some(fdt) => fdt,
+        None => return,
+    };
+    let slots = fdt
+        .nodes()
+        .filter(|node| node.is_compatible("virtio,mmio"))
+        .filter_map(|node| node.reg().next());
+    let mut count = 0;
+    for slot in slots.take(MAX_DEVICES) {
+        unsafe {
+            DEVICES[count] = slot;
+        }
+        count += 1;
+    }
+    unsafe {
+        DEVICE_COUNT = count;
+    }
+}
+
+/// The MMIO ranges of all virtio slots as (base, size).
+pub fn mmio_ranges() -> &'static [(usize, usize)] {
+    unsafe { &DEVICES[..DEVICE_COUNT] }
+}
+
+/// The physical base of the first device with the given device ID.
+pub fn find(device_id: u32) -> Option<usize> {
+    mmio_ranges().iter().map(|&(base, _)| base).find(|&base| {
+        let regs = phys_to_virt(base) as *const u32;
+        unsafe {
+            regs.add(MAGIC / 4).read_volatile() == MAGIC_VALUE
+                && regs.add(DEVICE_ID / 4).read_volatile() == device_id
+        }
+    })
+}
+
+/// A buffer descriptor, as the device reads it from the descriptor table.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct Descriptor {
+    pub addr: u64,
+    pub len: u32,
+    pub flags: u16,
+    pub next: u16,
+}
+
+// The rings as the spec lays them out. The driver fills the available
+// ring, the device the used ring.
+#[repr(C)]
+struct Available {
+    flags: u16,
+    idx: u16,
+    ring: [u16; QUEUE_SIZE],
+    event: u16,
+}
+
+#[repr(C)]
+struct UsedElem {
+    id: u32,
+    len: u32,
+}
+
+#[repr(C)]
+struct Used {
+    flags: u16,
+    idx: u16,
+    ring: [UsedElem; QUEUE_SIZE],
+    event: u16,
+}
+
+/// A device that has been set up, with its one virtqueue.
+pub struct Device {
+    base: usize,
+    // The queue lives in two pages of the DMA zone: the descriptor table
+    // and the available ring in the first one, and the used ring in the
+    // second, since the legacy layout wants it page aligned.
+    queue: DmaBuffer,
+    last_used: u16,
+}
+
+impl Device {
+    /// Reset the device at the physical address base and bring it up,
+    /// offering it the given features from the first feature word.
+    /// Returns None if the device does not play along.
+    pub fn new(base: usize, features: u32) -> Option<Device> {
+        let mut dev = Device {
+            base: phys_to_virt(base),
+            queue: alloc_aligned(2, PAGE_SIZE)?,
+            last_used: 0,
+        };
+        if dev.setup(features) {
+            Some(dev)
+        } else {
+            dev.write(STATUS, STATUS_FAILED);
+            crate::page::dealloc(dev.queue.vaddr);
+            None
+        }
+    }
+
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+
+    /// Read a 32-bit value from the configuration space.
+    pub fn config(&self, offset: usize) -> u32 {
+        self.read(CONFIG + offset)
+    }
+
+    fn legacy(&self) -> bool {
+        self.read(VERSION) == 1
+    }
+
+    // The initialization sequence from section 3.1.1 of the spec.
+    fn setup(&mut self, features: u32) -> bool {
+        if self.read(MAGIC) != MAGIC_VALUE || !matches!(self.read(VERSION), 1 | 2) {
+            return false;
+        }
+        self.write(STATUS, 0);
+        let mut status = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+        self.write(STATUS, status);
+
+        self.write(DEVICE_FEATURES_SEL, 0);
+        let offered = self.read(DEVICE_FEATURES);
+        self.write(DRIVER_FEATURES_SEL, 0);
+        self.write(DRIVER_FEATURES, offered & features);
+        if !self.legacy() {
+            self.write(DRIVER_FEATURES_SEL, 1);
+            self.write(DRIVER_FEATURES, FEATURE_VERSION_1);
+            status |= STATUS_FEATURES_OK;
+            self.write(STATUS, status);
+            if self.read(STATUS) & STATUS_FEATURES_OK == 0 {
+                return false;
+            }
+        }
+
+        self.write(QUEUE_SEL, 0);
+        let max = self.read(QUEUE_NUM_MAX) as usize;
+        if max < QUEUE_SIZE {
+            return false;
+        }
+        self.write(QUEUE_NUM, QUEUE_SIZE as u32);
+        let desc = self.queue.paddr as u64;
+        let avail = desc + (QUEUE_SIZE * size_of::<Descriptor>()) as u64;
+        let used = desc + PAGE_SIZE as u64;
+        if self.legacy() {
+            self.write(GUEST_PAGE_SIZE, PAGE_SIZE as u32);
+            self.write(QUEUE_ALIGN, PAGE_SIZE as u32);
+            self.write(QUEUE_PFN, (desc / PAGE_SIZE as u64) as u32);
+        } else {
+            self.write(QUEUE_DESC_LOW, desc as u32);
+            self.write(QUEUE_DESC_HIGH, (desc >> 32) as u32);
+            self.write(QUEUE_DRIVER_LOW, avail as u32);
+            self.write(QUEUE_DRIVER_HIGH, (avail >> 32) as u32);
+            self.write(QUEUE_DEVICE_LOW, used as u32);
+            self.write(QUEUE_DEVICE_HIGH, (used >> 32) as u32);
+            self.write(QUEUE_READY, 1);
+        }
+
+        status |= STATUS_DRIVER_OK;
+        self.write(STATUS, status);
+        true
+    }
+
+    fn descriptors(&self) -> *mut Descriptor {
+        self.queue.vaddr as *mut Descriptor
+    }
+
+    fn available(&self) -> *mut Available {
+        unsafe { self.descriptors().add(QUEUE_SIZE) as *mut Available }
+    }
+
+    fn used(&self) -> *mut Used {
+        unsafe { self.queue.vaddr.add(PAGE_SIZE) as *mut Used }
+    }
+
+    /// Hand the device a chain of buffers and wait until it is done with
+    /// it. The addresses are physical. Returns the number of bytes the
+    /// device wrote.
+    pub fn submit(&mut self, chain: &[Descriptor]) -> u32 {
+        assert!(!chain.is_empty() && chain.len() <= QUEUE_SIZE);
+        unsafe {
+            let desc = self.descriptors();
+            for (i, d) in chain.iter().enumerate() {
+                let mut d = *d;
+                if i + 1 < chain.len() {
+                    d.flags |= DESC_NEXT;
+                    d.next = i as u16 + 1;
+                }
+                desc.add(i).write_volatile(d);
+            }
+            let avail = self.available();
+            let idx = (*avail).idx;
+            (*avail).ring[idx as usize % QUEUE_SIZE] = 0;
+            // The device must see the descriptors before the new index,
+            // and the index before the notification.
+            fence(Ordering::SeqCst);
+            (&mut (*avail).idx as *mut u16).write_volatile(idx.wrapping_add(1));
+            fence(Ordering::SeqCst);
+            self.write(QUEUE_NOTIFY, 0);
+
+            let used = self.used();
+            while (&(*used).idx as *const u16).read_volatile() == self.last_used {}
+            fence(Ordering::SeqCst);
+            let elem = &(*used).ring[self.last_used as usize % QUEUE_SIZE];
+            let len = (&elem.len as *const u32).read_volatile();
+            self.last_used = self.last_used.wrapping_add(1);
+            // We do not use the interrupt, but keep it from staying pending.
+            self.write(INTERRUPT_ACK, self.read(INTERRUPT_STATUS));
+            len
+        }
+    }
+}
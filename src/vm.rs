@@ -0,0 +1,705 @@
+use crate::page::{
+    align_val, dealloc, find_entry, find_leaf, get_page, level_size, levels, map, map_range,
+    mappings, page_refs, paging_mode, phys_to_virt, put_page, set_swappable, unmap, unmap_range,
+    user_end, virt_to_phys, zalloc, Pte, PteError, PteFlags, Table, KERNEL_HALF, PAGE_ORDER,
+    PAGE_SIZE,
+};
+use crate::{asid, cpu, kmem, println, swap};
+use alloc::{boxed::Box, collections::BTreeMap, sync::Arc, vec::Vec};
+
+/// What the faulting instruction was trying to do. This follows the three
+/// page fault causes: 12 (instruction), 13 (load) and 15 (store/AMO).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Fetch,
+    Load,
+    Store,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultError {
+    /// The address is not inside any area of the address space.
+    NoRegion,
+    /// The area (or the page already mapped there) does not allow the
+    /// access.
+    ProtectionViolation,
+    /// We could not get a page to back the area with, or one for a page
+    /// table on the way to it.
+    OutOfMemory,
+    /// The pager of a file-backed area could not read the page.
+    IoError,
+    /// oom_kill() took the memory of the address space away.
+    Killed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VmError {
+    /// Addresses and lengths have to be multiples of PAGE_SIZE.
+    Misaligned,
+    /// The new area overlaps one that is already there.
+    Overlap,
+    /// The area reaches into the upper half, which is the kernel's.
+    OutOfRange,
+    /// The flags cannot go into a leaf.
+    BadFlags(PteError),
+    /// There was no page for a page table.
+    OutOfMemory,
+}
+
+/// Source of the contents of a file-backed area. There is no file system
+/// yet; this is the hook it will plug into.
+pub trait Pager {
+    /// Fill the PAGE_SIZE bytes at `page` with the contents at `offset`.
+    /// Returns false if the data could not be read.
+    fn read_page(&self, offset: usize, page: *mut u8) -> bool;
+}
+
+/// Where the memory behind an area comes from.
+#[derive(Clone)]
+pub enum Backing {
+    /// Zeroed pages, allocated on first touch.
+    Anonymous,
+    /// A fixed physical range, such as MMIO. This is the physical address
+    /// that the start of the area maps to. It is mapped right away.
+    Physical(usize),
+    /// Pages read in from a pager on first touch. `offset` is the position
+    /// in the file that the start of the area maps to.
+    File {
+        pager: Arc<dyn Pager>,
+        offset: usize,
+    },
+}
+
+impl Backing {
+    // The same backing, but for an area that starts `delta` bytes later.
+    fn advance(&self, delta: usize) -> Backing {
+        match self {
+            Backing::Anonymous => Backing::Anonymous,
+            Backing::Physical(paddr) => Backing::Physical(paddr + delta),
+            Backing::File { pager, offset } => Backing::File {
+                pager: pager.clone(),
+                offset: offset + delta,
+            },
+        }
+    }
+}
+
+/// A virtual memory area: a page-aligned range [start, end) with the same
+/// permissions and backing throughout.
+#[derive(Clone)]
+pub struct Vma {
+    pub start: usize,
+    pub end: usize,
+    pub flags: PteFlags,
+    pub backing: Backing,
+}
+
+impl Vma {
+    pub fn contains(&self, vaddr: usize) -> bool {
+        vaddr >= self.start && vaddr < self.end
+    }
+
+    pub fn allows(&self, access: Access) -> bool {
+        match access {
+            Access::Fetch => self.flags.is_executable(),
+            Access::Load => self.flags.is_readable(),
+            Access::Store => self.flags.is_writable(),
+        }
+    }
+}
+
+/// One virtual address space: a page table tree, the ASID it runs under
+/// (see asid.rs) and the areas that make it up, ordered by start address.
+/// Pages of anonymous and file-backed areas are only allocated when they
+/// are first touched (see page_fault()). The areas all live in the lower
+/// half; the upper half of the table is the kernel's.
+pub struct AddressSpace {
+    root: *mut Table,
+    // Generation and ASID, as asid::get() hands them out.
+    context: u64,
+    vmas: BTreeMap<usize, Vma>,
+    killed: bool,
+    // Set while a method is in the middle of changing the address space
+    // and may allocate, which can end up in oom_kill(). That one has to
+    // leave the address space alone then.
+    busy: bool,
+}
+
+// Every live address space, so the page fault handler can get from the
+// root table in satp back to the areas. Address spaces are always boxed,
+// so these pointers stay put. ADDRESS_SPACES_BUSY is set while the list
+// grows, which may allocate as well.
+static mut ADDRESS_SPACES: Vec<*mut AddressSpace> = Vec::new();
+static mut ADDRESS_SPACES_BUSY: bool = false;
+
+/// The address space whose page table is rooted at root_addr, if any.
+pub fn find_address_space(root_addr: usize) -> Option<&'static mut AddressSpace> {
+    unsafe {
+        ADDRESS_SPACES
+            .iter()
+            .find(|&&aspace| (*aspace).root as usize == root_addr)
+            .map(|&aspace| &mut *aspace)
+    }
+}
+
+impl AddressSpace {
+    /// Create an empty address space. It starts out with the kernel's half
+    /// of the kernel page table, so traps can be taken without switching
+    /// tables. It gets an ASID the first time it is activated.
+    pub fn new() -> Box<AddressSpace> {
+        let root = zalloc(1) as *mut Table;
+        assert!(!root.is_null(), "Out of memory for a root page table");
+        unsafe {
+            let kernel = &*kmem::get_page_table();
+            (*root).entries[KERNEL_HALF..].copy_from_slice(&kernel.entries[KERNEL_HALF..]);
+        }
+        let mut aspace = Box::new(AddressSpace {
+            root,
+            context: 0,
+            vmas: BTreeMap::new(),
+            killed: false,
+            busy: false,
+        });
+        unsafe {
+            ADDRESS_SPACES_BUSY = true;
+            ADDRESS_SPACES.push(&mut *aspace as *mut AddressSpace);
+            ADDRESS_SPACES_BUSY = false;
+        }
+        aspace
+    }
+
+    // Run f on the address space with busy set (see above).
+    fn while_busy<R>(&mut self, f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+        let busy = self.busy;
+        self.busy = true;
+        let result = f(self);
+        self.busy = busy;
+        result
+    }
+
+    pub fn root(&self) -> *mut Table {
+        self.root
+    }
+
+    /// The ASID we last ran under. It is only still ours while the
+    /// generation it came from is current.
+    pub fn asid(&self) -> u16 {
+        asid::asid(self.context)
+    }
+
+    /// The value to put into satp to switch to this address space. Only
+    /// good after activate() made sure the ASID is.
+    pub fn satp(&self) -> usize {
+        cpu::build_satp(
+            paging_mode(),
+            self.asid() as usize,
+            virt_to_phys(self.root as usize),
+        )
+    }
+
+    /// Switch this hart over to the address space. The TLB entries of the
+    /// one we leave stay where they are, tagged with its ASID.
+    pub fn activate(&mut self) {
+        asid::get(&mut self.context);
+        cpu::satp_write(self.satp());
+    }
+
+    fn table(&mut self) -> &mut Table {
+        unsafe { self.root.as_mut().unwrap() }
+    }
+
+    /// The area that covers vaddr.
+    pub fn lookup(&self, vaddr: usize) -> Option<&Vma> {
+        self.vmas
+            .range(..=vaddr)
+            .next_back()
+            .map(|(_, vma)| vma)
+            .filter(|vma| vma.contains(vaddr))
+    }
+
+    pub fn vmas(&self) -> impl Iterator<Item = &Vma> {
+        self.vmas.values()
+    }
+
+    /// Add an area of len bytes at start. Physical areas are mapped right
+    /// away, everything else is left to the page fault handler.
+    pub fn map(
+        &mut self,
+        start: usize,
+        len: usize,
+        flags: PteFlags,
+        backing: Backing,
+    ) -> Result<(), VmError> {
+        if start % PAGE_SIZE != 0 || len == 0 || len % PAGE_SIZE != 0 {
+            return Err(VmError::Misaligned);
+        }
+        if let Backing::Physical(paddr) = backing {
+            if paddr % PAGE_SIZE != 0 {
+                return Err(VmError::Misaligned);
+            }
+        }
+        flags.check_leaf().map_err(VmError::BadFlags)?;
+        let end = start + len;
+        if end > user_end() {
+            return Err(VmError::OutOfRange);
+        }
+        if self
+            .vmas
+            .range(..end)
+            .next_back()
+            .map_or(false, |(_, vma)| vma.end > start)
+        {
+            return Err(VmError::Overlap);
+        }
+
+        self.while_busy(|aspace| {
+            if let Backing::Physical(paddr) = backing {
+                if map_range(aspace.table(), start, paddr, len, flags).is_err() {
+                    // What did get mapped lies entirely inside the range, so
+                    // taking it out again splits nothing.
+                    let _ = unmap_range(aspace.table(), start, len);
+                    return Err(VmError::OutOfMemory);
+                }
+            }
+            aspace.vmas.insert(
+                start,
+                Vma {
+                    start,
+                    end,
+                    flags,
+                    backing,
+                },
+            );
+            Ok(())
+        })
+    }
+
+    // Make sure no area straddles addr, so [.., addr) and [addr, ..) can
+    // be changed independently.
+    fn split_at(&mut self, addr: usize) {
+        let (start, tail) = match self.vmas.range(..addr).next_back() {
+            Some((&start, vma)) if vma.end > addr => (
+                start,
+                Vma {
+                    start: addr,
+                    end: vma.end,
+                    flags: vma.flags,
+                    backing: vma.backing.advance(addr - vma.start),
+                },
+            ),
+            _ => return,
+        };
+        self.vmas.get_mut(&start).unwrap().end = addr;
+        self.vmas.insert(addr, tail);
+    }
+
+    // Drop our reference to every page that was faulted in for the area,
+    // and to the swap slots of those that were swapped out since. Physical
+    // areas do not own their pages.
+    fn release_pages(&mut self, vma: &Vma) {
+        if let Backing::Physical(_) = vma.backing {
+            return;
+        }
+        let asid = self.asid() as usize;
+        let mut vaddr = vma.start;
+        while vaddr < vma.end {
+            match find_entry(self.table(), vaddr) {
+                Some((leaf, 0)) if leaf.is_leaf() => {
+                    // Nothing may reach the page through us by the time it
+                    // can be freed.
+                    let page = phys_to_virt(leaf.phys_addr());
+                    *leaf = Pte::invalid();
+                    cpu::satp_fence(vaddr, asid);
+                    if page_refs(page) > 0 {
+                        put_page(page);
+                    }
+                }
+                Some((entry, 0)) if entry.is_swapped() => {
+                    swap::put_slot(entry.swap_slot());
+                    *entry = Pte::invalid();
+                }
+                _ => {}
+            }
+            vaddr += PAGE_SIZE;
+        }
+    }
+
+    /// Remove [start, start + len) from the address space. Areas that stick
+    /// out of the range are cut, and only the part inside goes away. Only
+    /// an area that sticks out past the end makes this allocate, so taking
+    /// whole areas away, as oom_kill() does, works without any memory.
+    ///
+    /// Cutting through a superpage of a physical area needs a page for a
+    /// new table. Without one, this stops with VmError::OutOfMemory; the
+    /// area it stopped at is still there, and the page fault handler maps
+    /// back whatever part of it was already gone.
+    pub fn unmap(&mut self, start: usize, len: usize) -> Result<(), VmError> {
+        self.while_busy(|aspace| aspace.unmap_areas(start, len))
+    }
+
+    fn unmap_areas(&mut self, start: usize, len: usize) -> Result<(), VmError> {
+        let end = start + align_val(len, PAGE_ORDER);
+        self.split_at(end);
+        // Walk down from the end, so every area we look at is the last one
+        // left below it.
+        while let Some((&s, vma)) = self.vmas.range(..end).next_back() {
+            if vma.end <= start {
+                break;
+            }
+            let from = s.max(start);
+            let gone = Vma {
+                start: from,
+                end: vma.end,
+                flags: vma.flags,
+                backing: vma.backing.advance(from - s),
+            };
+            self.release_pages(&gone);
+            unmap_range(self.table(), gone.start, gone.end - gone.start)
+                .map_err(|_| VmError::OutOfMemory)?;
+            if s < start {
+                // The area sticks out in front, so it keeps its key and
+                // just ends earlier.
+                self.vmas.get_mut(&s).unwrap().end = start;
+            } else {
+                self.vmas.remove(&s);
+            }
+        }
+        Ok(())
+    }
+
+    /// Change the permissions of [start, start + len). Pages that are
+    /// already mapped get the new permissions right away, except that a
+    /// COW page stays read-only until it has been copied.
+    pub fn protect(&mut self, start: usize, len: usize, flags: PteFlags) -> Result<(), VmError> {
+        flags.check_leaf().map_err(VmError::BadFlags)?;
+        self.while_busy(|aspace| aspace.protect_areas(start, len, flags));
+        Ok(())
+    }
+
+    fn protect_areas(&mut self, start: usize, len: usize, flags: PteFlags) {
+        let end = start + align_val(len, PAGE_ORDER);
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<usize> = self.vmas.range(start..end).map(|(&s, _)| s).collect();
+        for s in starts {
+            let vma = self.vmas.get_mut(&s).unwrap();
+            vma.flags = flags;
+            let (vstart, vend) = (vma.start, vma.end);
+            let mut vaddr = vstart;
+            while vaddr < vend {
+                if let Some((leaf, level)) = find_leaf(self.table(), vaddr) {
+                    // A clean page stays clean, so the clock can still drop
+                    // it.
+                    let mut new = flags | PteFlags::ACCESS;
+                    if leaf.is_dirty() {
+                        new |= PteFlags::DIRTY;
+                    }
+                    if leaf.flags().contains(PteFlags::COW) {
+                        new = new.difference(PteFlags::WRITE) | PteFlags::COW;
+                    }
+                    *leaf = leaf.with_flags(new).unwrap();
+                    cpu::satp_fence(vaddr, self.asid() as usize);
+                    vaddr = (vaddr & !(level_size(level) - 1)) + level_size(level);
+                } else {
+                    vaddr += PAGE_SIZE;
+                }
+            }
+        }
+    }
+
+    /// Duplicate this address space the cheap way, as fork() wants it: the
+    /// new one shares every page with us, and the first store on either
+    /// side copies just that page. Untouched parts still fault in on demand.
+    pub fn clone_cow(&mut self) -> Box<AddressSpace> {
+        self.while_busy(|parent| {
+            let mut child = AddressSpace::new();
+            child.while_busy(|child| parent.share_with(child));
+            child
+        })
+    }
+
+    // Make child, which is still empty, a COW copy of us.
+    fn share_with(&mut self, child: &mut AddressSpace) {
+        for i in 0..KERNEL_HALF {
+            child.table().entries[i] = clone_entry(self.table().entries[i], levels() - 1);
+        }
+        child.vmas = self.vmas.clone();
+        for vma in self.vmas.values() {
+            if let Backing::Physical(_) = vma.backing {
+                // Device memory and the like is shared as it is.
+                continue;
+            }
+            let mut vaddr = vma.start;
+            while vaddr < vma.end {
+                share_cow(self.root, child.root, vaddr);
+                vaddr += PAGE_SIZE;
+            }
+        }
+        // Our TLB may still hold the writable versions of pages that are
+        // COW now.
+        cpu::satp_fence_asid(self.asid() as usize);
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        // We are going away anyway, so oom_kill() need not bother.
+        self.busy = true;
+        let vmas: Vec<Vma> = self.vmas.values().cloned().collect();
+        for vma in vmas.iter() {
+            self.release_pages(vma);
+        }
+        // No cached walk may lead into the tables once they are freed.
+        cpu::satp_fence_asid(self.asid() as usize);
+        unmap(self.table());
+        dealloc(self.root as *mut u8);
+        let me = self as *mut AddressSpace;
+        unsafe {
+            ADDRESS_SPACES.retain(|&aspace| aspace != me);
+        }
+        asid::put(self.context);
+    }
+}
+
+/// Resolve a page fault at `vaddr` in the address space that is currently
+/// in satp. If the address belongs to an area and the page has not been
+/// touched yet, a page is mapped there, so the faulting instruction can
+/// simply be retried. The same goes for a page that was swapped out, which
+/// is read back in. Anything else is a real fault.
+///
+/// This runs from s_trap, which reaches the tables and the new page
+/// through the direct map.
+pub fn page_fault(vaddr: usize, access: Access) -> Result<(), FaultError> {
+    // satp holds the root table's physical page number in bits [43:0].
+    let root_addr = phys_to_virt((cpu::satp_read() & 0xfff_ffff_ffff) * PAGE_SIZE);
+    let aspace = find_address_space(root_addr).ok_or(FaultError::NoRegion)?;
+    if aspace.killed {
+        return Err(FaultError::Killed);
+    }
+    let vma = aspace.lookup(vaddr).ok_or(FaultError::NoRegion)?.clone();
+    if !vma.allows(access) {
+        return Err(FaultError::ProtectionViolation);
+    }
+
+    let page_addr = vaddr & !(PAGE_SIZE - 1);
+    let asid = aspace.asid() as usize;
+    match find_entry(aspace.table(), page_addr) {
+        Some((leaf, _)) if leaf.is_leaf() => {
+            if access == Access::Store && leaf.flags().contains(PteFlags::COW) {
+                return copy_on_write(leaf, vma.flags, page_addr, asid);
+            }
+            // The page is already there. Either another hart got here first
+            // and we saw a stale TLB entry, or the leaf itself does not allow
+            // this access.
+            let allowed = match access {
+                Access::Fetch => leaf.is_executable(),
+                Access::Load => leaf.is_readable(),
+                Access::Store => leaf.is_writable(),
+            };
+            if !allowed {
+                return Err(FaultError::ProtectionViolation);
+            }
+            // The clock clears A to see whether the page is still in use.
+            // Machines that fault instead of setting A and D themselves end
+            // up here.
+            let mut flags = leaf.flags() | PteFlags::ACCESS;
+            if access == Access::Store {
+                flags |= PteFlags::DIRTY;
+            }
+            *leaf = leaf.with_flags(flags).unwrap();
+        }
+        Some((entry, 0)) if entry.is_swapped() => swap::swap_in(entry, vma.flags)?,
+        _ => {
+            let offset = page_addr - vma.start;
+            let page = match vma.backing {
+                Backing::Anonymous => swap::alloc_page(),
+                Backing::Physical(paddr) => phys_to_virt(paddr + offset) as *mut u8,
+                Backing::File {
+                    ref pager,
+                    offset: base,
+                } => {
+                    let page = swap::alloc_page();
+                    if !page.is_null() && !pager.read_page(base + offset, page) {
+                        dealloc(page);
+                        return Err(FaultError::IoError);
+                    }
+                    page
+                }
+            };
+            if page.is_null() {
+                return Err(FaultError::OutOfMemory);
+            }
+            if map(
+                aspace.table(),
+                page_addr,
+                virt_to_phys(page as usize),
+                vma.flags,
+                0,
+            )
+            .is_err()
+            {
+                if !matches!(vma.backing, Backing::Physical(_)) {
+                    dealloc(page);
+                }
+                return Err(FaultError::OutOfMemory);
+            }
+            let (leaf, _) = find_leaf(aspace.table(), page_addr).unwrap();
+            // A page that has only been read can be faulted in again just
+            // the same, so it stays clean until the first store.
+            if access == Access::Store {
+                *leaf = leaf.with_flags(leaf.flags() | PteFlags::DIRTY).unwrap();
+            }
+            // Pages of file-backed areas are private copies as well, so
+            // only physical areas have to stay put.
+            if !matches!(vma.backing, Backing::Physical(_)) {
+                set_swappable(page as usize, leaf);
+            }
+        }
+    }
+    cpu::satp_fence(page_addr, asid);
+    Ok(())
+}
+
+// ///////////////////////////////////
+// / OUT OF MEMORY
+// ///////////////////////////////////
+
+impl AddressSpace {
+    /// Number of pages from the page allocator that are mapped into the
+    /// lower half, shared ones included.
+    pub fn resident_pages(&self) -> usize {
+        mappings(unsafe { &*self.root })
+            .take_while(|m| m.vaddr < user_end())
+            .filter(|m| page_refs(phys_to_virt(m.paddr)) > 0)
+            .map(|m| m.len / PAGE_SIZE)
+            .sum()
+    }
+
+    /// Whether oom_kill() took the memory of the address space away.
+    pub fn is_killed(&self) -> bool {
+        self.killed
+    }
+}
+
+/// Take all user memory away from the address space that has the most of
+/// it, as the last resort when memory runs out. There are no processes to
+/// kill yet, so the address space stays around without any areas, and
+/// every page fault in it fails with FaultError::Killed until its owner
+/// drops it. The address space this hart runs in is left alone, since we
+/// may be in the middle of a page fault for it, and so are those that a
+/// method further up the stack is busy changing. Returns the number of
+/// pages it had mapped, 0 if there was nothing to kill; shared pages only
+/// come free once the other side lets go of them as well.
+pub fn oom_kill() -> usize {
+    let current = phys_to_virt((cpu::satp_read() & 0xfff_ffff_ffff) * PAGE_SIZE);
+    let victim = unsafe {
+        if ADDRESS_SPACES_BUSY {
+            return 0;
+        }
+        ADDRESS_SPACES
+            .iter()
+            .map(|&aspace| &mut *aspace)
+            .filter(|aspace| aspace.root as usize != current && !aspace.killed && !aspace.busy)
+            .map(|aspace| {
+                let pages = aspace.resident_pages();
+                (aspace, pages)
+            })
+            .max_by_key(|&(_, pages)| pages)
+    };
+    let (aspace, pages) = match victim {
+        Some((aspace, pages)) if pages > 0 => (aspace, pages),
+        _ => return 0,
+    };
+    println!(
+        "Out of memory: killed the address space with ASID {} ({} pages)",
+        aspace.asid(),
+        pages
+    );
+    // Every area goes as a whole, which never cuts through a superpage.
+    let _ = aspace.unmap(0, user_end());
+    aspace.killed = true;
+    cpu::satp_fence_asid(aspace.asid() as usize);
+    pages
+}
+
+// ///////////////////////////////////
+// / COPY ON WRITE
+// ///////////////////////////////////
+
+// Give the writer its own copy of a COW page. If nobody else references
+// the page anymore, there is nothing to copy and it just becomes writable
+// again. Either way the writer's leaf is now the only one that maps the
+// page, so it can be swapped out. (A page that the other side ends up with
+// alone stays resident, since we do not know which entry that is.) leaf
+// maps vaddr in the address space with the given ASID.
+fn copy_on_write(
+    leaf: &mut Pte,
+    flags: PteFlags,
+    vaddr: usize,
+    asid: usize,
+) -> Result<(), FaultError> {
+    let old = phys_to_virt(leaf.phys_addr());
+    let flags = flags | PteFlags::ACCESS | PteFlags::DIRTY;
+    if page_refs(old) == 1 {
+        *leaf = leaf.with_flags(flags).unwrap();
+        set_swappable(old, leaf);
+        cpu::satp_fence(vaddr, asid);
+        return Ok(());
+    }
+
+    let new = swap::alloc_page();
+    if new.is_null() {
+        return Err(FaultError::OutOfMemory);
+    }
+    unsafe {
+        core::ptr::copy_nonoverlapping(old as *const u8, new, PAGE_SIZE);
+    }
+    *leaf = Pte::leaf(virt_to_phys(new as usize), flags).unwrap();
+    // Our reference to the old page only goes once no stale entry can
+    // reach it any more.
+    cpu::satp_fence(vaddr, asid);
+    put_page(old);
+    set_swappable(new as usize, leaf);
+    Ok(())
+}
+
+// Copy an entry of a table at the given level, together with the tree
+// below it if it is a branch. The leaves still point at the same pages;
+// clone_cow() takes care of the references.
+fn clone_entry(entry: Pte, level: usize) -> Pte {
+    if !entry.is_branch() || level == 0 {
+        return entry;
+    }
+    let src = unsafe { &*(phys_to_virt(entry.phys_addr()) as *const Table) };
+    let dst = zalloc(1) as *mut Table;
+    assert!(!dst.is_null(), "Out of memory while cloning a page table");
+    for (i, child) in src.entries.iter().enumerate() {
+        unsafe {
+            (*dst).entries[i] = clone_entry(*child, level - 1);
+        }
+    }
+    Pte::branch(virt_to_phys(dst as usize)).unwrap()
+}
+
+// Share the page at vaddr between two trees that were just cloned. It gets
+// one more reference, loses W if it had it and is marked COW on both
+// sides, even if it was read-only: protect() may make it writable later,
+// and a store then still has to copy it first. A swapped out page is shared by its slot instead; the
+// first side to touch it reads its own copy back in.
+fn share_cow(parent: *mut Table, child: *mut Table, vaddr: usize) {
+    let (parent, child) = unsafe { (&mut *parent, &mut *child) };
+    let leaf = match find_entry(parent, vaddr) {
+        Some((leaf, 0)) if leaf.is_leaf() && page_refs(phys_to_virt(leaf.phys_addr())) > 0 => leaf,
+        Some((entry, 0)) if entry.is_swapped() => {
+            swap::get_slot(entry.swap_slot());
+            return;
+        }
+        _ => return,
+    };
+    get_page(phys_to_virt(leaf.phys_addr()));
+    let flags = leaf.flags().difference(PteFlags::WRITE) | PteFlags::COW;
+    *leaf = leaf.with_flags(flags).unwrap();
+    if let Some((copy, 0)) = find_leaf(child, vaddr) {
+        *copy = *leaf;
+    }
+}
@@ -0,0 +1,165 @@
+// Virtually contiguous kernel allocations.
+// The page allocator can only hand out physically contiguous runs, which
+// get hard to find once memory is fragmented. vmalloc() takes single pages
+// from wherever they are and maps them next to each other in the vmalloc
+// region at the top of the kernel half (page::VMALLOC_START). Every area
+// is followed by an unmapped guard page, so running off its end faults
+// instead of scribbling over the next area.
+//
+// init() creates the root entries for the whole region before any address
+// space copies the kernel's half, and the tables below them are never
+// freed. That way every address space sees every area.
+//
+// Memory from vmalloc() is not physically contiguous, so it cannot go
+// through page::virt_to_phys() or be handed to a device.
+
+use crate::cpu;
+use crate::kmem;
+use crate::lock::IrqLock;
+use crate::page::{
+    align_val, dealloc, find_leaf, map, phys_to_virt, populate_root, virt_to_phys, zalloc, Pte,
+    PteFlags, Table, PAGE_ORDER, PAGE_SIZE, VMALLOC_END, VMALLOC_START,
+};
+use crate::println;
+use alloc::collections::BTreeMap;
+use core::ptr::null_mut;
+
+// Flushing page by page stops paying off somewhere around here.
+const FLUSH_ALL_PAGES: usize = 64;
+
+// Start of every area and its size in pages, without the guard page.
+static mut AREAS: Option<BTreeMap<usize, usize>> = None;
+// Guards AREAS and the tables below the region's root entries, which
+// vmalloc() fills in as it goes.
+static VMALLOC_LOCK: IrqLock<()> = IrqLock::new(());
+
+/// Set up the vmalloc region in the kernel's table. Call this after
+/// kmem::init() and before the first address space is created.
+pub fn init() {
+    let root = unsafe { &mut *kmem::get_page_table() };
+    populate_root(root, VMALLOC_START, VMALLOC_END);
+    unsafe {
+        AREAS = Some(BTreeMap::new());
+    }
+}
+
+fn areas() -> &'static mut BTreeMap<usize, usize> {
+    unsafe { AREAS.as_mut().expect("vmalloc before vmalloc::init") }
+}
+
+// First fit: the lowest address with room for pages and a guard page
+// behind them.
+fn find_gap(areas: &BTreeMap<usize, usize>, pages: usize) -> Option<usize> {
+    let need = (pages + 1) * PAGE_SIZE;
+    let mut start = VMALLOC_START;
+    for (&area, &area_pages) in areas.iter() {
+        if area - start >= need {
+            return Some(start);
+        }
+        start = area + (area_pages + 1) * PAGE_SIZE;
+    }
+    if VMALLOC_END - start >= need {
+        Some(start)
+    } else {
+        None
+    }
+}
+
+fn flush(start: usize, pages: usize) {
+    // The region is in every address space, so this goes for all ASIDs.
+    if pages > FLUSH_ALL_PAGES {
+        cpu::satp_fence_all();
+    } else {
+        for i in 0..pages {
+            cpu::satp_fence_vaddr(start + i * PAGE_SIZE);
+        }
+    }
+}
+
+// Unmap the first pages pages of the area at start and free them. The
+// tables stay.
+fn unmap_pages(root: &mut Table, start: usize, pages: usize) {
+    for i in 0..pages {
+        if let Some((leaf, 0)) = find_leaf(root, start + i * PAGE_SIZE) {
+            dealloc(phys_to_virt(leaf.phys_addr()) as *mut u8);
+            *leaf = Pte::invalid();
+        }
+    }
+    flush(start, pages);
+}
+
+/// Allocate size bytes of zeroed, virtually contiguous kernel memory,
+/// rounded up to whole pages. Returns null if there is not enough memory
+/// or address space left.
+pub fn vmalloc(size: usize) -> *mut u8 {
+    assert!(size > 0);
+    let pages = align_val(size, PAGE_ORDER) / PAGE_SIZE;
+    let _lock = VMALLOC_LOCK.lock();
+    let areas = areas();
+    let start = match find_gap(areas, pages) {
+        Some(start) => start,
+        None => return null_mut(),
+    };
+    let root = unsafe { &mut *kmem::get_page_table() };
+    for i in 0..pages {
+        let page = zalloc(1);
+        if page.is_null() {
+            unmap_pages(root, start, i);
+            return null_mut();
+        }
+        if map(
+            root,
+            start + i * PAGE_SIZE,
+            virt_to_phys(page as usize),
+            PteFlags::READ_WRITE,
+            0,
+        )
+        .is_err()
+        {
+            dealloc(page);
+            unmap_pages(root, start, i);
+            return null_mut();
+        }
+    }
+    areas.insert(start, pages);
+    flush(start, pages);
+    start as *mut u8
+}
+
+/// Free an area that vmalloc() returned.
+pub fn vfree(ptr: *mut u8) {
+    let start = ptr as usize;
+    let _lock = VMALLOC_LOCK.lock();
+    let pages = areas()
+        .remove(&start)
+        .unwrap_or_else(|| panic!("vfree of 0x{:x}, which vmalloc did not hand out", start));
+    let root = unsafe { &mut *kmem::get_page_table() };
+    unmap_pages(root, start, pages);
+}
+
+/// Size in bytes of the area at ptr, if vmalloc() handed it out.
+pub fn size(ptr: *const u8) -> Option<usize> {
+    let _lock = VMALLOC_LOCK.lock();
+    areas().get(&(ptr as usize)).map(|&pages| pages * PAGE_SIZE)
+}
+
+/// Print every area. This is mainly used for debugging.
+pub fn print_areas() {
+    println!("VMALLOC: 0x{:x} -> 0x{:x}", VMALLOC_START, VMALLOC_END);
+    let _lock = VMALLOC_LOCK.lock();
+    let mut total = 0;
+    for (&start, &pages) in areas().iter() {
+        println!(
+            "0x{:x} => 0x{:x}: {:>6} page(s)",
+            start,
+            start + pages * PAGE_SIZE,
+            pages
+        );
+        total += pages;
+    }
+    println!(
+        "Allocated: {:>6} pages ({:>10} bytes).",
+        total,
+        total * PAGE_SIZE
+    );
+}